@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use jpassword::models::credentials::Credentials;
+
+// A decrypted vault is decoded from whatever the blob held, so any bytes must be rejected with an error
+fuzz_target!(|json: &[u8]| {
+    if let Ok(credentials) = Credentials::decode(json) {
+        // Whatever decodes must survive being saved and decoded again
+        let encoded = serde_json::to_vec(&credentials).unwrap();
+        Credentials::decode(&encoded).unwrap();
+    }
+});
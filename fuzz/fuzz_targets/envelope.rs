@@ -0,0 +1,43 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use jpassword::crypto::{aead::{aead_open, aead_open_with_key, kek_unwrap}, envelope, hash::{Argon2Params, Kdf, PasswordHash}};
+
+/// Stands in for the legacy KDF, whose real cost would slow every legacy blob down
+const CHEAP_LEGACY_KDF: Kdf = Kdf::Pbkdf2Sha256 { iterations: 1 };
+
+/// Whether deriving a key with the KDF is cheap enough to run on every input. Costlier parameters
+/// within the limits would only make the fuzzer report running out of memory or time
+fn is_cheap(kdf: Kdf) -> bool {
+    match kdf {
+        Kdf::Argon2id(Argon2Params { memory_kib, iterations, .. }) => memory_kib <= 64 && iterations <= 2,
+        Kdf::Pbkdf2Sha256 { iterations } => iterations <= 1000,
+    }
+}
+
+// Stored blobs are read back from the database as is, so any bytes must be rejected with an error
+fuzz_target!(|blob: &[u8]| {
+    if let Ok(envelope) = envelope::parse(blob) {
+        envelope.associated_data(b"context");
+        if envelope.header.version > 0 {
+            // A parsed header encodes back to the bytes it was parsed from
+            assert_eq!(envelope.header.encode(), envelope.header_bytes);
+        }
+        // Whatever parses must hold KDF parameters the server is willing to run
+        assert!(envelope.header.kdf.map_or(true, |kdf| kdf.is_within_limits()));
+    }
+    let _ = aead_open_with_key(blob, &[0; 32], b"context");
+    // Runs the KDF the header claims, whose limits are checked above, so only cheap ones are run here
+    let is_cheap_to_open = match envelope::parse(blob) {
+        Ok(envelope) => envelope.header.kdf.is_none_or(is_cheap),
+        Err(_) => true,
+    };
+    if is_cheap_to_open {
+        let _ = aead_open(blob, b"password", &CHEAP_LEGACY_KDF, b"context");
+    }
+    let _ = kek_unwrap(blob, None, false, b"context");
+
+    // Password hashes are read back the same way, and their parameters are run just as blindly
+    if let Ok(hash) = std::str::from_utf8(blob).map(PasswordHash::from_phc) {
+        assert!(hash.map_or(true, |hash| hash.kdf.is_within_limits()));
+    }
+});
@@ -19,6 +19,11 @@ pub fn success(is_success: bool) -> Result<HttpResponse> {
     Ok(HttpResponse::Ok().json(SuccessDTO { success: is_success }))
 }
 
+/// An HTTP response telling the client the server is too busy to handle the request
+pub fn overloaded() -> Result<HttpResponse> {
+    Ok(HttpResponse::ServiceUnavailable().json(SuccessDTO { success: false }))
+}
+
 /// Serves as the asynchronous function for success, to be used in actix_web
 pub async fn success_async() -> Result<HttpResponse> {
     success(true)
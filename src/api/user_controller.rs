@@ -1,8 +1,13 @@
-use actix_web::{HttpResponse, web, Result};
-use serde::{Deserialize};
-use crate::models::{credentials::Credential, user::User};
+use actix_web::{HttpResponse, web, Result, http::header::{HeaderName, HeaderValue}};
+use serde::{Deserialize, Serialize};
+use crate::models::{credentials::{Credential, CredentialError, Credentials}, decoy::DuressAction, device::Device, emergency::Access,
+    key_slot::{KeySlot, SlotKind}, user::{User, UserError}};
 use crate::db::Pool;
-use super::{success};
+use rusqlite::Connection;
+use crate::config::settings::Settings;
+use crate::worker::{WorkerPool, WorkerError};
+use crate::crypto::secret::SecretString;
+use super::{success, overloaded, SuccessDTO};
 
 /// Represents a User of the application as provided in a POST request
 /// as a JSON object
@@ -11,7 +16,38 @@ pub struct UserDTO {
     /// The username of the user
     username: String,
     /// The hashed password of the user
-    password: String,
+    password: SecretString,
+}
+
+/// Represents the secret a User is opened with for everyday requests, either its password
+/// or the secret and PIN of one of its enrolled devices
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum SecretDTO {
+    /// The password of the user
+    Password {
+        password: SecretString,
+    },
+    /// An enrolled device of the user
+    Device {
+        /// The id of the device
+        device_id: i64,
+        /// The base64 encoded secret stored on the device
+        device_secret: SecretString,
+        /// The PIN of the device
+        pin: SecretString,
+    },
+}
+
+/// Represents a User of the application opened with its password or a device as provided
+/// in a POST request as a JSON object
+#[derive(Deserialize)]
+pub struct AccessDTO {
+    /// The username of the user
+    username: String,
+    /// The secret the user is opened with
+    #[serde(flatten)]
+    secret: SecretDTO,
 }
 
 /// Represents a user and their associated stored credentials to be returned over HTTP
@@ -19,96 +55,689 @@ pub struct UserDTO {
 #[derive(Deserialize)]
 pub struct CredentialDTO {
     /// A sub object that contains the user's credentials for this application
-    user: UserDTO,
+    user: AccessDTO,
     /// A vector of the user's associated credentials for other applications
     credential: Credential
 }
 
-/// An endpoint for the creation of a new user, returning an HTTP response
-/// that contains the User's new empty list of Credentials
-pub async fn signup(user: web::Json<UserDTO>, pool: web::Data<Pool>) -> Result<HttpResponse> {
-    let user_result = User::create(&pool.get().unwrap(), user.0.username, user.0.password);
-    if user_result.is_err() {
-        return success(false);
+/// Represents a change of a User's password as provided in a PUT request as a JSON object
+#[derive(Deserialize)]
+pub struct ChangePasswordDTO {
+    /// The username of the user
+    username: String,
+    /// The current password of the user
+    password: SecretString,
+    /// The new password of the user
+    new_password: SecretString,
+}
+
+/// Represents a change of a User's name as provided in a PUT request as a JSON object
+#[derive(Deserialize)]
+pub struct RenameDTO {
+    /// The current username of the user
+    username: String,
+    /// The password of the user
+    password: SecretString,
+    /// The new username of the user
+    new_username: String,
+}
+
+/// Represents a new duress password for a User as provided in a PUT request as a JSON object
+#[derive(Deserialize)]
+pub struct DuressDTO {
+    /// The username of the user
+    username: String,
+    /// The password of the user
+    password: SecretString,
+    /// The password opening the decoy vault
+    duress_password: SecretString,
+    /// What happens to the real vault when the duress password is used
+    action: DuressAction,
+}
+
+/// Represents the deletion of a User's account as provided in a DELETE request as a JSON object
+#[derive(Deserialize)]
+pub struct DeleteAccountDTO {
+    /// The username of the user
+    username: String,
+    /// The password of the user
+    password: SecretString,
+    /// The username typed again to confirm the deletion
+    confirmation: String,
+}
+
+/// Represents a recovery code and a new password as provided in a POST request
+/// as a JSON object
+#[derive(Deserialize)]
+pub struct RecoverDTO {
+    /// The username of the user
+    username: String,
+    /// The recovery code of the user
+    recovery_code: SecretString,
+    /// The new password of the user
+    password: SecretString,
+}
+
+/// Represents enough shares of a split secret and a new password as provided in a POST request
+/// as a JSON object
+#[derive(Deserialize)]
+pub struct RecoverSharesDTO {
+    /// The username of the user
+    username: String,
+    /// The shares of the user's split secret
+    shares: Vec<SecretString>,
+    /// The new password of the user
+    password: SecretString,
+}
+
+/// Represents a key file used in place of the password as provided in a POST request
+/// as a JSON object
+#[derive(Deserialize)]
+pub struct UnlockDTO {
+    /// The username of the user
+    username: String,
+    /// The base64 encoded key file of the user
+    key_file: SecretString,
+}
+
+/// Represents a request to add a key slot as provided in a POST request as a JSON object
+#[derive(Deserialize)]
+pub struct SlotDTO {
+    /// A sub object that contains the user's credentials for this application
+    user: UserDTO,
+    /// The kind of key slot to add
+    kind: SlotKind,
+}
+
+/// Represents a request to split a new secret into shares as provided in a POST request
+/// as a JSON object
+#[derive(Deserialize)]
+pub struct SharesDTO {
+    /// A sub object that contains the user's credentials for this application
+    user: UserDTO,
+    /// The number of shares needed to unlock the vault
+    threshold: u8,
+    /// The number of shares to make
+    count: u8,
+}
+
+/// Represents a grant of emergency access to another User as provided in a POST request
+/// as a JSON object
+#[derive(Deserialize)]
+pub struct GrantDTO {
+    /// A sub object that contains the user's credentials for this application
+    user: UserDTO,
+    /// The username of the user to grant access to
+    grantee: String,
+    /// What the grantee may do once the grant is approved
+    access: Access,
+    /// How many days the user has to reject a request for access
+    wait_days: u32,
+}
+
+/// Represents a takeover of another User's account as provided in a POST request as a JSON object
+#[derive(Deserialize)]
+pub struct TakeOverDTO {
+    /// A sub object that contains the grantee's credentials for this application
+    user: UserDTO,
+    /// The new password of the owner of the grant
+    password: SecretString,
+}
+
+/// Represents a request to enroll a device as provided in a POST request as a JSON object
+#[derive(Deserialize)]
+pub struct DeviceDTO {
+    /// A sub object that contains the user's credentials for this application
+    user: UserDTO,
+    /// The name of the device
+    name: String,
+    /// The PIN the device will be unlocked with
+    pin: SecretString,
+}
+
+/// Represents a newly enrolled device along with its secret to be returned over HTTP as a JSON object
+#[derive(Serialize)]
+pub struct NewDeviceDTO {
+    /// The new device
+    #[serde(flatten)]
+    device: Device,
+    /// The base64 encoded secret to store on the device
+    device_secret: SecretString,
+}
+
+/// Represents a User's credentials along with a new recovery code to be returned over HTTP
+/// as a JSON object
+#[derive(Serialize)]
+pub struct RecoveryCodeDTO {
+    /// The User's list of credentials
+    #[serde(flatten)]
+    credentials: Credentials,
+    /// The recovery code to show the User once
+    recovery_code: SecretString,
+}
+
+/// Represents a new key slot along with its secret to be returned over HTTP as a JSON object
+#[derive(Serialize)]
+pub struct NewSlotDTO {
+    /// The new key slot
+    #[serde(flatten)]
+    slot: KeySlot,
+    /// The recovery code or base64 encoded key file to show the User once
+    secret: SecretString,
+}
+
+/// Represents a new split secret's key slot along with its shares to be returned over HTTP
+/// as a JSON object
+#[derive(Serialize)]
+pub struct NewSharesDTO {
+    /// The new key slot
+    #[serde(flatten)]
+    slot: KeySlot,
+    /// The printable shares to hand out once
+    shares: Vec<SecretString>,
+}
+
+/// Turns the result of work run on the WorkerPool into an HTTP response
+/// that contains the result as JSON on success, usually the User's list of credentials
+///
+/// # Arguments
+///
+/// * `result` - The result of the work, holding what to return on success
+fn respond<T: Serialize>(result: std::result::Result<std::result::Result<T, UserError>, WorkerError>) -> Result<HttpResponse> {
+    match result {
+        Ok(Ok(body)) => Ok(HttpResponse::Ok().json(body)),
+        Ok(Err(_)) => success(false),
+        Err(WorkerError::Overloaded) => overloaded(),
+        Err(WorkerError::Panicked) => Ok(HttpResponse::InternalServerError().finish())
     }
-    Ok(HttpResponse::Ok().json(user_result.unwrap().credentials))
 }
 
-/// An endpoint for fetching an existing user, returning an HTTP response
-/// that contains the User's saved list of credentials
-pub async fn login(user: web::Json<UserDTO>, pool: web::Data<Pool>) -> Result<HttpResponse> {
-    let user_result = User::login(&pool.get().unwrap(), user.0.username, user.0.password);
-    if user_result.is_err() {
-        return success(false);
+/// Opens an existing User with its password or one of its devices, returning the User
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `settings` - The server configuration
+/// * `access` - The username and secret to open the User with
+fn open(conn: &Connection, settings: &Settings, access: AccessDTO) -> std::result::Result<User, UserError> {
+    match access.secret {
+        SecretDTO::Password { password } => User::login(conn, settings, access.username, password),
+        SecretDTO::Device { device_id, device_secret, pin } =>
+            User::unlock_with_device(conn, settings, access.username, device_id, &device_secret, &pin),
     }
-    Ok(HttpResponse::Ok().json(user_result.unwrap().credentials))
+}
+
+/// An endpoint for the creation of a new user, returning an HTTP response
+/// that contains the User's new empty list of Credentials and its recovery code
+pub async fn signup(user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let mut conn = pool.get().map_err(|_| UserError::Internal)?;
+        User::create(&mut conn, &settings, user.username, user.password)
+            .map(|(user, recovery_code)| RecoveryCodeDTO { credentials: user.credentials, recovery_code })
+    }).await)
+}
+
+/// An endpoint for changing the password of an existing User, returning an HTTP response
+/// that contains the User's saved list of credentials
+pub async fn change_password(change: web::Json<ChangePasswordDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let change = change.into_inner();
+
+    respond(workers.run(move || {
+        let mut conn = pool.get().map_err(|_| UserError::Internal)?;
+        User::change_password(&mut conn, &settings, change.username, change.password, change.new_password)
+            .map(|user| user.credentials)
+    }).await)
+}
+
+/// An endpoint for renaming an existing User, returning an HTTP response
+/// that contains the User's saved list of credentials
+pub async fn rename(rename: web::Json<RenameDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let rename = rename.into_inner();
+
+    respond(workers.run(move || {
+        let mut conn = pool.get().map_err(|_| UserError::Internal)?;
+        User::rename(&mut conn, &settings, rename.username, rename.password, rename.new_username)
+            .map(|user| user.credentials)
+    }).await)
+}
+
+/// An endpoint for deleting an existing User and everything it owns, returning an HTTP response
+/// that denotes the success of the deletion
+pub async fn delete_account(delete: web::Json<DeleteAccountDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let delete = delete.into_inner();
+
+    respond(workers.run(move || {
+        let mut conn = pool.get().map_err(|_| UserError::Internal)?;
+        User::delete_account(&mut conn, &settings, delete.username, delete.password, &delete.confirmation)
+            .map(|_| SuccessDTO { success: true })
+    }).await)
+}
+
+/// An endpoint for registering a duress password for an existing User, returning an HTTP response
+/// that contains the User's saved list of credentials
+pub async fn set_duress(duress: web::Json<DuressDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let duress = duress.into_inner();
+
+    respond(workers.run(move || {
+        let mut conn = pool.get().map_err(|_| UserError::Internal)?;
+        User::set_duress_password(&mut conn, &settings, duress.username, duress.password, duress.duress_password,
+            duress.action)
+            .map(|user| user.credentials)
+    }).await)
+}
+
+/// An endpoint for removing the duress password of an existing User, returning an HTTP response
+/// that contains the User's saved list of credentials
+pub async fn clear_duress(user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.clear_duress_password(&conn, &settings)?;
+        Ok(user.credentials)
+    }).await)
+}
+
+/// An endpoint for showing whether the vault key of an existing User is escrowed, returning an HTTP response
+/// that contains the escrow status and its audit trail
+pub async fn escrow(user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.escrow_status(&conn, &settings)
+    }).await)
+}
+
+/// An endpoint for setting a new password with a recovery code, returning an HTTP response
+/// that contains the User's saved list of credentials and its new recovery code
+pub async fn recover(recover: web::Json<RecoverDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let recover = recover.into_inner();
+
+    respond(workers.run(move || {
+        let mut conn = pool.get().map_err(|_| UserError::Internal)?;
+        User::recover(&mut conn, &settings, recover.username, &recover.recovery_code, recover.password)
+            .map(|(user, recovery_code)| RecoveryCodeDTO { credentials: user.credentials, recovery_code })
+    }).await)
+}
+
+/// An endpoint for setting a new password with enough shares of a split secret, returning an HTTP response
+/// that contains the User's saved list of credentials
+pub async fn recover_shares(recover: web::Json<RecoverSharesDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let recover = recover.into_inner();
+
+    respond(workers.run(move || {
+        let mut conn = pool.get().map_err(|_| UserError::Internal)?;
+        User::recover_with_shares(&mut conn, &settings, recover.username, &recover.shares, recover.password)
+            .map(|user| user.credentials)
+    }).await)
+}
+
+/// An endpoint for fetching an existing user with a key file, returning an HTTP response
+/// that contains the User's saved list of credentials
+pub async fn unlock(unlock: web::Json<UnlockDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let unlock = unlock.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        User::unlock_with_key_file(&conn, &settings, unlock.username, &unlock.key_file)
+            .map(|user| user.credentials)
+    }).await)
+}
+
+/// An endpoint for fetching an existing user with its password or a device, returning an HTTP response
+/// that contains the User's saved list of credentials
+pub async fn login(user: web::Json<AccessDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        open(&conn, &settings, user)
+            .map(|user| user.credentials)
+    }).await)
 }
 
 /// An endpoint for adding a new credentials to an existing  User, returning an HTTP response
 /// that contains the User's new list of credentials
-pub async fn create(credential: web::Json<CredentialDTO>, pool: web::Data<Pool>) -> Result<HttpResponse> {
-    let user_result = User::login(&pool.get().unwrap(), credential.0.user.username, credential.0.user.password);
-    
-    if user_result.is_err() {
-        return success(false);
-    }
-    let mut user = user_result.unwrap();
-    let credential = credential.0.credential;
-
-    user.credentials.create(
-        credential.name, 
-        credential.url,
-        credential.username,
-        credential.password);
-
-    match user.save(&pool.get().unwrap()) {
-        Ok(()) => Ok(HttpResponse::Ok().json(user.credentials)),
-        Err(()) => success(false)
-    }
+pub async fn create(credential: web::Json<CredentialDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let CredentialDTO { user, credential } = credential.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let mut user = open(&conn, &settings, user)?;
+
+        user.credentials.create(
+            settings.rng.as_ref(),
+            credential.name,
+            credential.item).map_err(credential_error)?;
+
+        user.save(&conn, &settings)?;
+        Ok(user.credentials)
+    }).await)
 }
 
 /// An endpoint for deleting a saved credential of an existing User, returning an HTTP response
 /// that contains the User's new list of credentials
-pub async fn delete(index: web::Path<u32>, 
-    user: web::Json<UserDTO>, pool: web::Data<Pool>) -> Result<HttpResponse> {
+pub async fn delete(id: web::Path<String>,
+    user: web::Json<AccessDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let by_index = is_index(&id);
+    let user = user.into_inner();
 
-    let user_result = User::login(&pool.get().unwrap(), user.0.username, user.0.password);
+    let response = respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let mut user = open(&conn, &settings, user)?;
 
-    if user_result.is_err() {
-        return success(false);
-    }
-    let mut user = user_result.unwrap();
-
-    match user.credentials.delete(index.into_inner() as usize) {
-        Ok(()) => {
-            match user.save(&pool.get().unwrap()) {
-                Ok(()) => Ok(HttpResponse::Ok().json(user.credentials)),
-                Err(()) => success(false)
-            }
-        },
-        Err(()) => success(false)
-    }
+        let id = credential_id(&user.credentials, &id)?;
+        user.credentials.delete(&id).map_err(|_| UserError::NotFound)?;
+        user.save(&conn, &settings)?;
+        Ok(user.credentials)
+    }).await);
+    if by_index { deprecated(response) } else { response }
 }
 
 /// An endpoint for updating a saved credential of an existing User, returning an HTTP response
 /// that contains the User's new list of credentials after the update
-pub async fn update(index: web::Path<u32>, 
-    credential: web::Json<CredentialDTO>, pool: web::Data<Pool>) -> Result<HttpResponse> {
+pub async fn update(id: web::Path<String>,
+    credential: web::Json<CredentialDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let by_index = is_index(&id);
+    let CredentialDTO { user, credential } = credential.into_inner();
 
-    let user_result = User::login(&pool.get().unwrap(), credential.0.user.username, credential.0.user.password);
+    let response = respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let mut user = open(&conn, &settings, user)?;
 
-    if user_result.is_err() {
-        return success(false);
+        let id = credential_id(&user.credentials, &id)?;
+        user.credentials.update(&id, credential).map_err(credential_error)?;
+        user.save(&conn, &settings)?;
+        Ok(user.credentials)
+    }).await);
+    if by_index { deprecated(response) } else { response }
+}
+
+/// Returns whether the `{id}` of a credential route is a position in the list of credentials
+/// rather than a credential's id
+///
+/// # Arguments
+///
+/// * `id` - The `{id}` of the route
+fn is_index(id: &str) -> bool {
+    id.parse::<usize>().is_ok()
+}
+
+/// Resolves the `{id}` of a credential route to a credential's id. A position in the list of credentials
+/// is still accepted while clients move to ids, as it shifts whenever an earlier credential is deleted
+///
+/// # Arguments
+///
+/// * `credentials` - The User's credentials
+/// * `id` - The `{id}` of the route, a credential's id or its position
+fn credential_id(credentials: &Credentials, id: &str) -> std::result::Result<String, UserError> {
+    match id.parse::<usize>() {
+        Ok(index) => credentials.id_at(index).map(str::to_string).ok_or(UserError::NotFound),
+        Err(_) => Ok(id.to_string())
     }
-    let mut user = user_result.unwrap();
-
-    match user.credentials.update(index.into_inner() as usize, credential.0.credential) {
-        Ok(()) => {
-            match user.save(&pool.get().unwrap()) {
-                Ok(()) => Ok(HttpResponse::Ok().json(user.credentials)),
-                Err(()) => success(false)
-            }
-        },
-        Err(()) => success(false)
+}
+
+/// Returns the reason an operation on a User fails when one of its credentials cannot be added or updated
+///
+/// # Arguments
+///
+/// * `err` - The reason the credential cannot be added or updated
+fn credential_error(err: CredentialError) -> UserError {
+    match err {
+        CredentialError::NotFound => UserError::NotFound,
+        CredentialError::InvalidItem => UserError::InvalidItem,
+        CredentialError::Internal => UserError::Internal,
     }
-}
\ No newline at end of file
+}
+
+/// Marks a response to a request addressing a credential by its position with a `Deprecation` header
+///
+/// # Arguments
+///
+/// * `response` - The response to mark
+fn deprecated(response: Result<HttpResponse>) -> Result<HttpResponse> {
+    response.map(|mut response| {
+        response.headers_mut().insert(HeaderName::from_static("deprecation"), HeaderValue::from_static("true"));
+        response
+    })
+}
+
+/// An endpoint for listing the key slots of an existing User, returning an HTTP response
+/// that contains the User's key slots
+pub async fn slots(user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.slots(&conn)
+    }).await)
+}
+
+/// An endpoint for adding a recovery code or key file slot to an existing User, returning
+/// an HTTP response that contains the new slot and its secret
+pub async fn create_slot(slot: web::Json<SlotDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let SlotDTO { user, kind } = slot.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.add_slot(&conn, &settings, kind)
+            .map(|(slot, secret)| NewSlotDTO { slot, secret })
+    }).await)
+}
+
+/// An endpoint for splitting a new secret of an existing User into shares, returning an HTTP response
+/// that contains the new slot and its shares
+pub async fn create_shares(shares: web::Json<SharesDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let SharesDTO { user, threshold, count } = shares.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.add_shares(&conn, &settings, threshold, count)
+            .map(|(slot, shares)| NewSharesDTO { slot, shares })
+    }).await)
+}
+
+/// An endpoint for revoking a key slot of an existing User, returning an HTTP response
+/// that contains the User's remaining key slots
+pub async fn revoke_slot(id: web::Path<i64>,
+    user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.revoke_slot(&conn, id)?;
+        user.slots(&conn)
+    }).await)
+}
+
+/// An endpoint for listing the emergency access grants an existing User owns or was granted,
+/// returning an HTTP response that contains the grants
+pub async fn grants(user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.emergency_grants(&conn)
+    }).await)
+}
+
+/// An endpoint for granting another User emergency access to an existing User's vault,
+/// returning an HTTP response that contains the new grant
+pub async fn create_grant(grant: web::Json<GrantDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let GrantDTO { user, grantee, access, wait_days } = grant.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.grant_emergency_access(&conn, &settings, &grantee, access, wait_days)
+    }).await)
+}
+
+/// An endpoint for revoking an emergency access grant an existing User owns, returning
+/// an HTTP response that contains the User's remaining grants
+pub async fn revoke_grant(id: web::Path<i64>,
+    user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.revoke_emergency_access(&conn, id)?;
+        user.emergency_grants(&conn)
+    }).await)
+}
+
+/// An endpoint for requesting access through an emergency access grant, returning an HTTP response
+/// that contains the grant
+pub async fn request_grant(id: web::Path<i64>,
+    user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.request_emergency_access(&conn, &settings, id)
+    }).await)
+}
+
+/// An endpoint for approving a request for an emergency access grant before its waiting period is over,
+/// returning an HTTP response that contains the grant
+pub async fn approve_grant(id: web::Path<i64>,
+    user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.approve_emergency_access(&conn, id)
+    }).await)
+}
+
+/// An endpoint for rejecting a request for an emergency access grant, returning an HTTP response
+/// that contains the grant
+pub async fn reject_grant(id: web::Path<i64>,
+    user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.reject_emergency_access(&conn, id)
+    }).await)
+}
+
+/// An endpoint for reading the vault of the owner of an approved emergency access grant,
+/// returning an HTTP response that contains the owner's list of credentials
+pub async fn access_grant(id: web::Path<i64>,
+    user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.open_emergency_access(&conn, &settings, id)
+            .map(|owner| owner.credentials)
+    }).await)
+}
+
+/// An endpoint for setting a new password for the owner of an approved takeover grant,
+/// returning an HTTP response that contains the owner's saved list of credentials
+pub async fn take_over(id: web::Path<i64>,
+    take_over: web::Json<TakeOverDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let TakeOverDTO { user, password } = take_over.into_inner();
+
+    respond(workers.run(move || {
+        let mut conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.take_over(&mut conn, &settings, id, &password)
+            .map(|owner| owner.credentials)
+    }).await)
+}
+
+/// An endpoint for listing the devices enrolled by an existing User, returning an HTTP response
+/// that contains the User's devices
+pub async fn devices(user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.devices(&conn)
+    }).await)
+}
+
+/// An endpoint for enrolling a device of an existing User, returning an HTTP response
+/// that contains the new device and its secret
+pub async fn enroll_device(device: web::Json<DeviceDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let DeviceDTO { user, name, pin } = device.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.enroll_device(&conn, &settings, &name, &pin)
+            .map(|(device, device_secret)| NewDeviceDTO { device, device_secret })
+    }).await)
+}
+
+/// An endpoint for revoking a device of an existing User, returning an HTTP response
+/// that contains the User's remaining devices
+pub async fn revoke_device(id: web::Path<i64>,
+    user: web::Json<UserDTO>, pool: web::Data<Pool>,
+    settings: web::Data<Settings>, workers: web::Data<WorkerPool>) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let user = user.into_inner();
+
+    respond(workers.run(move || {
+        let conn = pool.get().map_err(|_| UserError::Internal)?;
+        let user = User::login(&conn, &settings, user.username, user.password)?;
+        user.revoke_device(&conn, id)?;
+        user.devices(&conn)
+    }).await)
+}
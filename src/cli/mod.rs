@@ -0,0 +1,194 @@
+use crate::config::settings::Settings;
+use crate::crypto::keyring::KeyRing;
+use crate::db::{self, Pool};
+use crate::models::user::User;
+use rusqlite::params;
+use crate::crypto::{hash::Kdf, keypair::{self, KEY_LEN}, rand::SystemRng, secret::{SecretBytes, SecretString}};
+use crate::models::escrow;
+use std::{collections::HashSet, fs, io::{self, Write}, path::Path, str, time::Duration};
+
+/// Runs an administrative command given on the command line in place of the server
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments following the executable
+/// * `settings` - The server configuration
+/// * `pool` - The pool of connections to the database
+/// * `db_file` - The path of the database
+pub fn run(args: &[String], settings: &Settings, pool: &Pool, db_file: &Path) -> io::Result<()> {
+    match args.first().map(String::as_str) {
+        Some("rotate-index-key") => rotate_index_key(settings, pool),
+        Some("rotate-kek") => rotate_kek(settings, pool, db_file),
+        Some("calibrate") => calibrate(settings, args.get(1)),
+        Some("escrow-keygen") if args.len() == 2 => escrow_keygen(settings, Path::new(&args[1])),
+        Some("escrow-recover") if args.len() == 4 => escrow_recover(settings, pool, &args[1], Path::new(&args[2]), &args[3]),
+        _ => {
+            eprintln!("Usage: jpassword [rotate-index-key | init-kek | rotate-kek | calibrate [target ms] | escrow-keygen <private key file> \
+                | escrow-recover <username> <private key file> <officer>]");
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown command"))
+        }
+    }
+}
+
+/// Adds a new current index key to the key file, dropping older keys no user is indexed under.
+/// Users indexed under older keys are reindexed on their next login.
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `pool` - The pool of connections to the database
+fn rotate_index_key(settings: &Settings, pool: &Pool) -> io::Result<()> {
+    let conn = pool.get().map_err(io::Error::other)?;
+    let mut keys = KeyRing::load(&settings.index_key_file)?;
+    let replaced = keys.current().id;
+    let id = keys.rotate(settings.rng.as_ref())?;
+
+    let mut stmt = conn.prepare("SELECT DISTINCT index_key FROM users WHERE index_key IS NOT NULL")
+        .map_err(io::Error::other)?;
+    let in_use = stmt.query_map(params![], |row| row.get(0))
+        .and_then(|rows| rows.collect::<Result<HashSet<u32>, _>>())
+        .map_err(io::Error::other)?;
+
+    // A running server keeps indexing new users under the replaced key until it is restarted
+    keys.retain(|key_id| key_id == replaced || in_use.contains(&key_id));
+    keys.save(&settings.index_key_file)?;
+
+    let stale: u32 = conn.query_row("SELECT COUNT(*) FROM users WHERE index_key IS NOT ?", params![id], |row| row.get(0))
+        .map_err(io::Error::other)?;
+    println!("Index key {} is now current, keeping keys {:?} in {}", id, keys.ids(), settings.index_key_file.display());
+    println!("{} users are reindexed under the new key on their next login. Restart the server to use it", stale);
+    Ok(())
+}
+
+/// Creates the key-encryption key file named by `JPASSWORD_KEK_FILE` holding a new key, refusing to replace
+/// one that exists. Runs before the server configuration is read, which refuses a key file that is set but missing
+pub fn init_kek() -> io::Result<()> {
+    let kek_file = Settings::kek_file_from_env()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "JPASSWORD_KEK_FILE is not set"))?;
+    let keks = KeyRing::create(&SystemRng::new(), &kek_file)?;
+
+    println!("Key-encryption key {} is in {}. Back it up apart from the database", keks.current().id, kek_file.display());
+    println!("Run `jpassword rotate-kek` to wrap existing users, or start the server with JPASSWORD_KEK_MIGRATE=true");
+    Ok(())
+}
+
+/// Adds a new current key-encryption key to the key file and rewraps every stored blob with it,
+/// dropping older keys once no row is wrapped with them. Also wraps rows stored before
+/// a key-encryption key was configured. Refuses to run while a server is running, as it would hold
+/// the old keys and could not unwrap the rewrapped rows until restarted.
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `pool` - The pool of connections to the database
+/// * `db_file` - The path of the database
+fn rotate_kek(settings: &Settings, pool: &Pool, db_file: &Path) -> io::Result<()> {
+    let kek_file = settings.kek_file.as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "JPASSWORD_KEK_FILE is not set"))?;
+    // Also keeps a server from starting until the rotation is done
+    let _lock = db::lock(db_file, true).map_err(|err| match err.kind() {
+        io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::WouldBlock,
+            "a server is running against the database, stop it before rotating the key-encryption key"),
+        _ => err,
+    })?;
+    let mut conn = pool.get().map_err(io::Error::other)?;
+    let mut keks = KeyRing::load(kek_file)?;
+    let id = keks.rotate(settings.rng.as_ref())?;
+
+    // The new key is saved before anything is wrapped with it
+    keks.save(kek_file)?;
+    let (rewrapped, skipped) = User::rewrap_all(&mut conn, settings.rng.as_ref(), &keks)
+        .map_err(|err| io::Error::other(format!("could not rewrap users: {:?}", err)))?;
+
+    if skipped == 0 {
+        keks.retain(|_| false);
+        keks.save(kek_file)?;
+    }
+
+    println!("Key-encryption key {} is now current, keeping keys {:?} in {}", id, keks.ids(), kek_file.display());
+    println!("{} users were rewrapped and {} were saved while rewrapping. Start the server to use the new key",
+        rewrapped, skipped);
+    Ok(())
+}
+
+/// Benchmarks the KDF on this machine and prints parameters that hit a target latency,
+/// to be set in the environment of the server
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration, whose Argon2id parameters are the floor of the calibration
+/// * `target_ms` - How many milliseconds a hash should take, `JPASSWORD_KDF_TARGET_MS` or 250 if not given
+fn calibrate(settings: &Settings, target_ms: Option<&String>) -> io::Result<()> {
+    let target_ms = match target_ms {
+        Some(target_ms) => target_ms.parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the target is not a number of milliseconds"))?,
+        None => settings.kdf_target.map_or(250, |target| target.as_millis() as u64),
+    };
+
+    let mut settings = settings.clone();
+    let elapsed = settings.calibrate_kdf(Duration::from_millis(target_ms))
+        .map_err(|_| io::Error::other("could not run the KDF"))?;
+
+    if let Kdf::Argon2id(params) = settings.kdf {
+        println!("A hash takes {:?} with", elapsed);
+        println!("JPASSWORD_ARGON2_MEMORY_KIB={}", params.memory_kib);
+        println!("JPASSWORD_ARGON2_ITERATIONS={}", params.iterations);
+        println!("JPASSWORD_ARGON2_PARALLELISM={}", params.parallelism);
+    }
+    Ok(())
+}
+
+/// Generates the organisation's escrow keypair, writing the private key to a new file readable only by
+/// its owner and printing the public key to configure the server with. The private key file is meant
+/// to be moved offline
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `private_key_file` - The path the private key is written to, which must not exist
+fn escrow_keygen(settings: &Settings, private_key_file: &Path) -> io::Result<()> {
+    let (private_key, public_key) = keypair::new_keypair(settings.rng.as_ref())
+        .map_err(|_| io::Error::other("could not generate a keypair"))?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(private_key_file)?;
+    file.write_all(SecretString::from(hex::encode(private_key.as_bytes())).as_bytes())?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+
+    println!("The escrow private key is in {}. Keep it offline", private_key_file.display());
+    println!("JPASSWORD_ESCROW_PUBLIC_KEY={}", hex::encode(&public_key));
+    println!("Fingerprint {}", escrow::fingerprint(&public_key));
+    Ok(())
+}
+
+/// Opens the vault of a User with the escrow private key and sets a new random password, printed once
+/// for the recovery officer to hand over. The recovery is recorded in the User's audit trail
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `pool` - The pool of connections to the database
+/// * `username` - The name of the User
+/// * `private_key_file` - The path of the escrow private key written by `escrow-keygen`
+/// * `officer` - The name of the recovery officer
+fn escrow_recover(settings: &Settings, pool: &Pool, username: &str, private_key_file: &Path, officer: &str) -> io::Result<()> {
+    let hex_key = SecretString::from(fs::read_to_string(private_key_file)?);
+    let hex_key = str::from_utf8(hex_key.as_bytes()).map_err(io::Error::other)?;
+    let mut private_key = SecretBytes::zeroed(KEY_LEN);
+    hex::decode_to_slice(hex_key.trim(), private_key.as_mut_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the private key file is not 32 hex encoded bytes"))?;
+
+    let mut conn = pool.get().map_err(io::Error::other)?;
+    let (_, password) = User::recover_with_escrow(&mut conn, settings, username, &private_key, officer)
+        .map_err(|err| io::Error::other(format!("could not recover {}: {:?}", username, err)))?;
+
+    let password = str::from_utf8(password.as_bytes()).map_err(io::Error::other)?;
+    println!("The new password of {} is:", username);
+    println!("{}", password);
+    println!("It is shown once. Every device of {} was revoked and the recovery was recorded as made by {}", username, officer);
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::{sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+/// A source of the current time, the system's in production and a fixed one
+/// when behaviour over time must be reproducible
+pub trait Clock: Send + Sync {
+    /// The current time
+    fn now(&self) -> SystemTime;
+
+    /// The current time in whole seconds since the Unix epoch, as stored in the database
+    fn unix_seconds(&self) -> i64 {
+        self.now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64)
+    }
+}
+
+/// The system's time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A time that only changes when advanced
+pub struct FixedClock(Mutex<SystemTime>);
+
+impl FixedClock {
+    /// Creates a clock stopped at a time
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time the clock reads
+    pub fn new(time: SystemTime) -> Self {
+        FixedClock(Mutex::new(time))
+    }
+
+    /// Moves the clock forward
+    ///
+    /// # Arguments
+    ///
+    /// * `by` - How far to move the clock
+    pub fn advance(&self, by: Duration) {
+        let mut time = self.0.lock().unwrap();
+        *time += by;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
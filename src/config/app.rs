@@ -8,9 +8,34 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
             .service(web::resource("/").route(web::get().to(api::success_async)))
             .service(web::resource("/signup").route(web::post().to(user_controller::signup)))
             .service(web::resource("/login").route(web::post().to(user_controller::login)))
+            .service(web::resource("/account").route(web::delete().to(user_controller::delete_account)))
+            .service(web::resource("/account/username").route(web::put().to(user_controller::rename)))
+            .service(web::resource("/account/password").route(web::put().to(user_controller::change_password)))
+            .service(web::resource("/account/duress")
+                .route(web::put().to(user_controller::set_duress))
+                .route(web::delete().to(user_controller::clear_duress)))
+            .service(web::resource("/account/escrow").route(web::post().to(user_controller::escrow)))
+            .service(web::resource("/recover").route(web::post().to(user_controller::recover)))
+            .service(web::resource("/recover/shares").route(web::post().to(user_controller::recover_shares)))
+            .service(web::resource("/unlock").route(web::post().to(user_controller::unlock)))
             .service(web::resource("/credential").route(web::post().to(user_controller::create)))
             .service(web::resource("/credential/{id}")
                 .route(web::delete().to(user_controller::delete))
                 .route(web::put().to(user_controller::update)))
+            .service(web::resource("/slots").route(web::post().to(user_controller::slots)))
+            .service(web::resource("/slot").route(web::post().to(user_controller::create_slot)))
+            .service(web::resource("/slot/shares").route(web::post().to(user_controller::create_shares)))
+            .service(web::resource("/slot/{id}").route(web::delete().to(user_controller::revoke_slot)))
+            .service(web::resource("/devices").route(web::post().to(user_controller::devices)))
+            .service(web::resource("/device").route(web::post().to(user_controller::enroll_device)))
+            .service(web::resource("/device/{id}").route(web::delete().to(user_controller::revoke_device)))
+            .service(web::resource("/emergency/grants").route(web::post().to(user_controller::grants)))
+            .service(web::resource("/emergency/grant").route(web::post().to(user_controller::create_grant)))
+            .service(web::resource("/emergency/grant/{id}").route(web::delete().to(user_controller::revoke_grant)))
+            .service(web::resource("/emergency/grant/{id}/request").route(web::post().to(user_controller::request_grant)))
+            .service(web::resource("/emergency/grant/{id}/approve").route(web::post().to(user_controller::approve_grant)))
+            .service(web::resource("/emergency/grant/{id}/reject").route(web::post().to(user_controller::reject_grant)))
+            .service(web::resource("/emergency/grant/{id}/access").route(web::post().to(user_controller::access_grant)))
+            .service(web::resource("/emergency/grant/{id}/takeover").route(web::post().to(user_controller::take_over)))
     );
 }
\ No newline at end of file
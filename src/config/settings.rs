@@ -0,0 +1,211 @@
+use crate::crypto::{aead::AeadAlgorithm, hash::{calibrate, Argon2Params, Kdf, MAX_ITERATIONS, MAX_MEMORY_KIB, MAX_PARALLELISM}, keypair, keyring::KeyRing, rand::{Rng, SystemRng}};
+use crate::clock::{Clock, SystemClock};
+use std::{env, io, path::PathBuf, str::FromStr, sync::Arc, thread, time::Duration};
+
+/// Represents how the memory of the process is protected, read apart from the rest of the configuration
+/// so the protection can be applied before `Settings::from_env` loads any key
+#[derive(Clone, Copy)]
+pub struct MemorySettings {
+    /// The number of threads running KDF and SQLite work, each of which may lock secret buffers
+    pub worker_threads: usize,
+    /// Whether buffers holding keys and decrypted data are locked into memory
+    pub lock_memory: bool,
+    /// Whether the process is kept from writing core dumps
+    pub disable_core_dumps: bool,
+}
+
+impl MemorySettings {
+    /// Reads the memory protection from the environment, falling back to defaults for unset variables
+    ///
+    /// * `JPASSWORD_WORKER_THREADS` - The number of threads running KDF and SQLite work
+    /// * `JPASSWORD_LOCK_MEMORY` - `true` to lock buffers holding keys and decrypted data into memory
+    /// * `JPASSWORD_DISABLE_CORE_DUMPS` - `false` to allow the process to write core dumps
+    ///
+    /// Panics if there are no worker threads.
+    pub fn from_env() -> Self {
+        let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let worker_threads = env_or("JPASSWORD_WORKER_THREADS", cpus);
+        // Every request waits on a worker thread, so with none the server would never answer
+        if worker_threads == 0 {
+            panic!("JPASSWORD_WORKER_THREADS must be at least 1");
+        }
+        MemorySettings {
+            worker_threads,
+            lock_memory: env_or("JPASSWORD_LOCK_MEMORY", false),
+            disable_core_dumps: env_or("JPASSWORD_DISABLE_CORE_DUMPS", true),
+        }
+    }
+}
+
+/// Represents the server configuration, read from `JPASSWORD_*` environment variables
+#[derive(Clone)]
+pub struct Settings {
+    /// The KDF and parameters used for new password hashes and password-based keys
+    pub kdf: Kdf,
+    /// How long a password hash should take, if the KDF is calibrated at startup
+    pub kdf_target: Option<Duration>,
+    /// The AEAD algorithm new users' data is sealed with
+    pub aead_algorithm: AeadAlgorithm,
+    /// Whether users' data is sealed in envelopes that commit to their key
+    pub key_commitment: bool,
+    /// The number of threads running KDF and SQLite work
+    pub worker_threads: usize,
+    /// The number of requests that may wait for a worker thread before being rejected
+    pub worker_queue_len: usize,
+    /// Whether accounts may register a duress password opening a decoy vault, which makes
+    /// every login run the KDF twice
+    pub duress: bool,
+    /// Whether buffers holding keys and decrypted data are locked into memory
+    pub lock_memory: bool,
+    /// Whether the process is kept from writing core dumps
+    pub disable_core_dumps: bool,
+    /// The path of the file holding the keys usernames are indexed under
+    pub index_key_file: PathBuf,
+    /// The keys usernames are indexed under, read from `index_key_file`
+    pub index_keys: Arc<KeyRing>,
+    /// The path of the file holding the server-side key-encryption keys, if configured
+    pub kek_file: Option<PathBuf>,
+    /// The server-side key-encryption keys every stored blob is wrapped with, read from `kek_file`
+    pub keks: Option<Arc<KeyRing>>,
+    /// Whether blobs stored before key-encryption keys were configured are accepted at login and wrapped there,
+    /// rather than refused
+    pub kek_migrate: bool,
+    /// The organisation's escrow public key every vault key is also wrapped to, if escrow is enabled
+    pub escrow_public_key: Option<Vec<u8>>,
+    /// The source of every salt, nonce and key
+    pub rng: Arc<dyn Rng>,
+    /// The source of the current time
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Settings {
+    /// Reads the server configuration from the environment, including the variables read by
+    /// `MemorySettings::from_env`, falling back to defaults for unset variables
+    ///
+    /// * `JPASSWORD_ARGON2_MEMORY_KIB` - The Argon2id memory cost in KiB
+    /// * `JPASSWORD_ARGON2_ITERATIONS` - The Argon2id time cost
+    /// * `JPASSWORD_ARGON2_PARALLELISM` - The Argon2id parallelism
+    /// * `JPASSWORD_KDF_TARGET_MS` - If set, the Argon2id time cost is calibrated at startup so a hash
+    ///   takes this many milliseconds, never going below `JPASSWORD_ARGON2_ITERATIONS`
+    /// * `JPASSWORD_AEAD_ALGORITHM` - `aes-256-gcm` or `chacha20-poly1305`
+    /// * `JPASSWORD_KEY_COMMITMENT` - `true` to seal users' data in envelopes that commit to their key
+    /// * `JPASSWORD_WORKER_QUEUE` - The number of requests that may wait for a worker thread
+    /// * `JPASSWORD_DURESS` - `true` to let accounts register a duress password, doubling the cost of a login
+    /// * `JPASSWORD_INDEX_KEY_FILE` - The path of the index key file, created if missing
+    /// * `JPASSWORD_KEK_FILE` - The path of the key-encryption key file, created by `jpassword init-kek`.
+    ///   Stored blobs are only wrapped if set
+    /// * `JPASSWORD_KEK_MIGRATE` - `true` to wrap users stored before the key-encryption key file was set at their next login,
+    ///   rather than refusing them
+    /// * `JPASSWORD_ESCROW_PUBLIC_KEY` - The hex encoded X25519 escrow public key every vault key is also wrapped to.
+    ///   Vault keys are only escrowed if set
+    ///
+    /// Panics if a key file cannot be read or created, if the key-encryption key file is set but missing, if the Argon2id parameters exceed the limits stored
+    /// parameters are checked against, if there are no worker threads, or if the escrow public key is malformed.
+    pub fn from_env() -> Self {
+        let defaults = Argon2Params::default();
+        let memory = MemorySettings::from_env();
+        let index_key_file = env_or("JPASSWORD_INDEX_KEY_FILE", beside_executable("jpassword.key"));
+        let rng = SystemRng::new();
+        let index_keys = KeyRing::load_or_create(&rng, &index_key_file)
+            .unwrap_or_else(|err| panic!("Could not load {}: {}", index_key_file.display(), err));
+        let kdf_target_ms: u64 = env_or("JPASSWORD_KDF_TARGET_MS", 0);
+        let kek_file = Self::kek_file_from_env();
+        // A new key could not unwrap any stored row, so a missing key file is never replaced here
+        let keks = kek_file.as_ref().map(|kek_file| KeyRing::load(kek_file).unwrap_or_else(|err| match err.kind() {
+            io::ErrorKind::NotFound => panic!("The key-encryption key file {} does not exist. Restore it from its backup, \
+                or run `jpassword init-kek` if no user has been wrapped yet", kek_file.display()),
+            _ => panic!("Could not load {}: {}", kek_file.display(), err),
+        }));
+        let kdf = Kdf::Argon2id(Argon2Params {
+            memory_kib: env_or("JPASSWORD_ARGON2_MEMORY_KIB", defaults.memory_kib),
+            iterations: env_or("JPASSWORD_ARGON2_ITERATIONS", defaults.iterations),
+            parallelism: env_or("JPASSWORD_ARGON2_PARALLELISM", defaults.parallelism),
+        });
+        // Hashes made with parameters beyond the limits could never be verified
+        if !kdf.is_within_limits() {
+            panic!("The Argon2id parameters exceed {} KiB, {} iterations or {} lanes", MAX_MEMORY_KIB, MAX_ITERATIONS, MAX_PARALLELISM);
+        }
+        Settings {
+            kdf,
+            kdf_target: if kdf_target_ms == 0 { None } else { Some(Duration::from_millis(kdf_target_ms)) },
+            aead_algorithm: env_or("JPASSWORD_AEAD_ALGORITHM", AeadAlgorithm::Aes256Gcm),
+            key_commitment: env_or("JPASSWORD_KEY_COMMITMENT", false),
+            worker_threads: memory.worker_threads,
+            worker_queue_len: env_or("JPASSWORD_WORKER_QUEUE", 64),
+            duress: env_or("JPASSWORD_DURESS", false),
+            lock_memory: memory.lock_memory,
+            disable_core_dumps: memory.disable_core_dumps,
+            index_key_file,
+            index_keys: Arc::new(index_keys),
+            kek_file,
+            keks: keks.map(Arc::new),
+            kek_migrate: env_or("JPASSWORD_KEK_MIGRATE", false),
+            escrow_public_key: env::var("JPASSWORD_ESCROW_PUBLIC_KEY").ok().map(|public_key| parse_public_key(&public_key)
+                .unwrap_or_else(|_| panic!("JPASSWORD_ESCROW_PUBLIC_KEY is not {} hex encoded bytes", keypair::KEY_LEN))),
+            rng: Arc::new(rng),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Reads the path of the key-encryption key file from `JPASSWORD_KEK_FILE`, if set
+    pub fn kek_file_from_env() -> Option<PathBuf> {
+        env::var_os("JPASSWORD_KEK_FILE").map(PathBuf::from)
+    }
+
+    /// Calibrates the Argon2id time cost on this machine so a hash takes about `target`,
+    /// keeping the configured parameters as a floor. Returns how long a hash now takes
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - How long a hash should take
+    #[allow(clippy::result_unit_err)]
+    pub fn calibrate_kdf(&mut self, target: Duration) -> Result<Duration, ()> {
+        let floor = match self.kdf {
+            Kdf::Argon2id(params) => params,
+            Kdf::Pbkdf2Sha256 { .. } => Argon2Params::default(),
+        };
+        let (params, elapsed) = calibrate(target, floor)?;
+        self.kdf = Kdf::Argon2id(params);
+        Ok(elapsed)
+    }
+}
+
+/// The path of a file in the directory of the server executable
+///
+/// # Arguments
+///
+/// * `file_name` - The name of the file
+fn beside_executable(file_name: &str) -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    path.push(file_name);
+    path
+}
+
+/// Reads and parses an environment variable, returning `default` if it is unset.
+/// Panics if the variable is set but cannot be parsed.
+///
+/// # Arguments
+///
+/// * `name` - The name of the environment variable
+/// * `default` - The value to use when the variable is unset
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse()
+            .unwrap_or_else(|_| panic!("{} has an invalid value: {}", name, value)),
+        Err(_) => default,
+    }
+}
+
+/// Reads a hex encoded public key
+///
+/// # Arguments
+///
+/// * `public_key` - The hex encoded public key
+fn parse_public_key(public_key: &str) -> Result<Vec<u8>, ()> {
+    let public_key = hex::decode(public_key.trim()).map_err(|_| ())?;
+    if public_key.len() != keypair::KEY_LEN {
+        return Err(());
+    }
+    Ok(public_key)
+}
@@ -1,101 +1,256 @@
 
-use ring::{aead, digest, error};
-use super::{hash::{pbkdf2_rand_salt, pbkdf2_with_salt, SALT_LEN}, rand::{generate_rand_vec}};
+use ring::{aead, constant_time, digest, error};
+use std::str::FromStr;
+use super::{envelope::{self, Envelope, Header, COMMITMENT_LEN}, hash::{hkdf_expand, Kdf, SUBKEY_LEN},
+    keyring::KeyRing, rand::{generate_rand_vec, Rng}, secret::SecretBytes};
 
-static AEAD_ALG: &'static aead::Algorithm = &aead::AES_256_GCM; 
+/// The length of a key derived from a password
+const DERIVED_KEY_LEN: usize = digest::SHA256_OUTPUT_LEN;
 
-const CREDENTIAL_LEN: usize = digest::SHA256_OUTPUT_LEN;
-pub type Credential = [u8; CREDENTIAL_LEN];
+const COMMITMENT_INFO: &[u8] = b"jpassword key commitment";
+const COMMITTED_KEY_INFO: &[u8] = b"jpassword committed encryption";
+
+/// An AEAD algorithm a blob can be sealed with
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AeadAlgorithm {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl AeadAlgorithm {
+    /// The id of this algorithm in the envelope format
+    pub fn id(self) -> u8 {
+        match self {
+            AeadAlgorithm::Aes256Gcm => 1,
+            AeadAlgorithm::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    /// Finds the algorithm with an envelope format id
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of the algorithm in the envelope format
+    pub fn from_id(id: u8) -> Result<Self, ()> {
+        match id {
+            1 => Ok(AeadAlgorithm::Aes256Gcm),
+            2 => Ok(AeadAlgorithm::ChaCha20Poly1305),
+            _ => Err(())
+        }
+    }
+
+    /// The length of the nonces used by this algorithm
+    pub fn nonce_len(self) -> usize {
+        self.ring().nonce_len()
+    }
+
+    /// The ring implementation of this algorithm
+    fn ring(self) -> &'static aead::Algorithm {
+        match self {
+            AeadAlgorithm::Aes256Gcm => &aead::AES_256_GCM,
+            AeadAlgorithm::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+impl FromStr for AeadAlgorithm {
+    type Err = ();
+
+    /// Parses an algorithm from its configuration name,
+    /// either `aes-256-gcm` or `chacha20-poly1305`
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "aes-256-gcm" => Ok(AeadAlgorithm::Aes256Gcm),
+            "chacha20-poly1305" => Ok(AeadAlgorithm::ChaCha20Poly1305),
+            _ => Err(())
+        }
+    }
+}
 
 /// Creates a key to be used for sealing/opening plaintext/ciphertext via AEAD
 fn make_key<K: aead::BoundKey<OneNonceSequence>>(
     algorithm: &'static aead::Algorithm,
     key: &[u8],
     nonce: aead::Nonce,
-) -> K {
-    let key = aead::UnboundKey::new(algorithm, key).unwrap();
+) -> Result<K, ()> {
+    let key = aead::UnboundKey::new(algorithm, key).map_err(|_| ())?;
     let nonce_sequence = OneNonceSequence::new(nonce);
-    K::new(key, nonce_sequence)
+    Ok(K::new(key, nonce_sequence))
+}
+
+/// Seals a plaintext with a raw key returning an envelope holding the header and ciphertext on Ok
+///
+/// # Arguments
+///
+/// * `rng` - The source of the nonce
+/// * `plaintext` - The plaintext array of bytes to seal
+/// * `key` - The key to seal the plaintext with
+/// * `algorithm` - The AEAD algorithm to seal with
+/// * `commit` - Whether the envelope commits to the key, so it can only be opened by that key
+/// * `context` - The context to bind the envelope to, authenticated but not stored
+pub fn aead_seal_with_key(rng: &dyn Rng, plaintext: &[u8], key: &[u8], algorithm: AeadAlgorithm, commit: bool,
+    context: &[u8]) -> Result<Vec<u8>, ()> {
+    let nonce = generate_rand_vec(rng, algorithm.nonce_len())?;
+    let header = Header::new(algorithm, None, Vec::new(), nonce);
+
+    if commit {
+        let (commitment, committed_key) = commit_to_key(key)?;
+        seal_with_header(&Header { commitment, ..header }, committed_key.as_bytes(), plaintext, context)
+    } else {
+        seal_with_header(&header, key, plaintext, context)
+    }
+}
+
+/// Seals a plaintext with a key under a given header, returning the encoded envelope
+///
+/// # Arguments
+///
+/// * `header` - The header of the envelope, holding the algorithm and nonce to seal with
+/// * `key` - The key to seal the plaintext with, the committed subkey if the header holds a commitment
+/// * `plaintext` - The plaintext array of bytes to seal
+/// * `context` - The context to bind the envelope to, authenticated but not stored
+pub fn seal_with_header(header: &Header, key: &[u8], plaintext: &[u8], context: &[u8]) -> Result<Vec<u8>, ()> {
+    let nonce = aead::Nonce::try_assume_unique_for_key(&header.nonce).map_err(|_| ())?;
+    let mut sealing_key: aead::SealingKey<OneNonceSequence> = make_key(header.algorithm.ring(), key, nonce)?;
+
+    let encoded_header = header.encode();
+    let aad = envelope::associated_data(header.version, &encoded_header, context);
+
+    // The plaintext is copied straight into the envelope and sealed there, so the only
+    // copy of it left in memory is the caller's. The envelope is a secret buffer until sealed.
+    let tag_len = header.algorithm.ring().tag_len();
+    let mut envelope = SecretBytes::with_capacity(encoded_header.len() + plaintext.len() + tag_len);
+    envelope.as_mut_vec().extend_from_slice(&encoded_header);
+    envelope.as_mut_vec().extend_from_slice(plaintext);
+    let tag = sealing_key.seal_in_place_separate_tag(aead::Aad::from(&aad), &mut envelope.as_mut_bytes()[encoded_header.len()..])
+        .map_err(|_| ())?;
+
+    let mut envelope = envelope.into_vec();
+    envelope.extend_from_slice(tag.as_ref());
+    Ok(envelope)
 }
 
-/// Seals a plaintext with a password returning the ciphertext, salt, and nonce on Ok
+/// Opens a password-based envelope, returning the plaintext
 ///
 /// # Arguments
 ///
-/// * `plaintext` - The plaintext vector of bytes to seal
+/// * `sealed` - A password-based envelope, sealed by seal_with_header under a Header that records its KDF,
+///   or a legacy `ciphertext || salt || nonce` blob
 /// * `password` - The array of bytes used to create a password-based key
-pub fn aead_seal(plaintext: &Vec<u8>, password: &[u8]) -> Result<Vec<u8>, ()> {
-    
-    let mut password_cred: Credential = [0u8; CREDENTIAL_LEN];
-    let salt = pbkdf2_rand_salt(password, &mut password_cred);
+/// * `legacy_kdf` - The KDF and parameters to use if `sealed` is a legacy blob that does not record its own
+/// * `context` - The context the envelope is expected to be bound to
+pub fn aead_open(sealed: &[u8], password: &[u8], legacy_kdf: &Kdf, context: &[u8]) -> Result<SecretBytes, ()> {
+    let envelope = envelope::parse(sealed).map_err(|_| ())?;
+    let header = &envelope.header;
+    if header.kek_id.is_some() {
+        return Err(());
+    }
 
+    let mut derived_key = SecretBytes::zeroed(DERIVED_KEY_LEN);
+    header.kdf.unwrap_or(*legacy_kdf).derive(password, &header.salt, derived_key.as_mut_bytes())?;
 
-    let nonce_vec = generate_rand_vec(AEAD_ALG.nonce_len()).unwrap();
-    let nonce = aead::Nonce::try_assume_unique_for_key(&nonce_vec).unwrap();
+    open_envelope(&envelope, derived_key.as_bytes(), context)
+}
 
-    let mut key: aead::SealingKey<OneNonceSequence> = make_key(AEAD_ALG, &password_cred, nonce);
-    let mut ciphertext = plaintext.clone();
-    let seal_result = key.seal_in_place_append_tag(aead::Aad::empty(), &mut ciphertext);
-    
-    if seal_result.is_err() {    
-        return Err(())    
+/// Opens an envelope sealed with a raw key by aead_seal_with_key, returning the plaintext
+/// # Arguments
+///
+/// * `sealed` - The vector of bytes returned by aead_seal_with_key
+/// * `key` - The key the plaintext was sealed with
+/// * `context` - The context the envelope is expected to be bound to
+pub fn aead_open_with_key(sealed: &[u8], key: &[u8], context: &[u8]) -> Result<SecretBytes, ()> {
+    let envelope = envelope::parse(sealed).map_err(|_| ())?;
+    if envelope.header.kdf.is_some() || envelope.header.kek_id.is_some() {
+        return Err(());
     }
 
-    ciphertext.extend(&salt);
-    ciphertext.extend(&nonce_vec);
-    Ok(ciphertext)
+    open_envelope(&envelope, key, context)
 }
 
-/// Breaks down the vector of bytes returned by aead_seal
-/// To extract the ciphertext, salt, and nonce used for opening the ciphertext.
+/// Wraps a stored blob in an envelope sealed with the current server-side key-encryption key
 /// # Arguments
 ///
-/// * `ciphertext_salt_nonce` - The vector of bytes returned by aead_seal
-fn extract_ciphertext_salt_nonce(ciphertext_salt_nonce: &Vec<u8>) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
-    let ciphertext_slice = &ciphertext_salt_nonce
-        [..ciphertext_salt_nonce.len() - AEAD_ALG.nonce_len() - SALT_LEN];
-    let mut ciphertext = Vec::<u8>::new();
-    ciphertext.extend_from_slice(ciphertext_slice);
-
-    let salt_slice = &ciphertext_salt_nonce
-        [ciphertext_salt_nonce.len() - AEAD_ALG.nonce_len() - SALT_LEN .. ciphertext_salt_nonce.len() - AEAD_ALG.nonce_len()];
-    let mut salt = Vec::<u8>::new();
-    salt.extend_from_slice(salt_slice);
-
-    let nonce_slice = &ciphertext_salt_nonce
-        [ciphertext_salt_nonce.len() - AEAD_ALG.nonce_len()..];
-    let mut nonce = Vec::<u8>::new();
-    nonce.extend_from_slice(nonce_slice);
-
-    (ciphertext, salt, nonce)
+/// * `rng` - The source of the nonce
+/// * `blob` - The stored blob to wrap
+/// * `keks` - The server-side key-encryption keys
+/// * `context` - The context to bind the envelope to, authenticated but not stored
+pub fn kek_wrap(rng: &dyn Rng, blob: &[u8], keks: &KeyRing, context: &[u8]) -> Result<Vec<u8>, ()> {
+    let kek = keks.current();
+    let algorithm = AeadAlgorithm::Aes256Gcm;
+    let nonce = generate_rand_vec(rng, algorithm.nonce_len())?;
+    let header = Header { kek_id: Some(kek.id), ..Header::new(algorithm, None, Vec::new(), nonce) };
+
+    seal_with_header(&header, kek.as_bytes(), blob, context)
 }
 
-/// Opens an AEAD sealed ciphertext, returning the plaintext
+/// Unwraps a stored blob wrapped by kek_wrap, returning the blob along with the id of
+/// the key-encryption key it was wrapped with. Blobs stored before they were wrapped
+/// are returned as they are with no id if no key-encryption keys are configured or
+/// `allow_unwrapped` is set, and refused otherwise so a wrapped blob cannot be swapped for an unwrapped one.
+///
 /// # Arguments
 ///
-/// * `ciphertext_salt_nonce` - The vector of bytes returned by aead_seal
-pub fn aead_open(ciphertext_salt_nonce: &Vec<u8>, password: &[u8]) -> Result<Vec<u8>, ()> {
-    let (ciphertext, salt, nonce) = extract_ciphertext_salt_nonce(&ciphertext_salt_nonce);
+/// * `blob` - The stored blob
+/// * `keks` - The server-side key-encryption keys, `None` if none are configured
+/// * `allow_unwrapped` - Whether blobs not yet wrapped are accepted, for a caller that wraps them at once
+/// * `context` - The context the envelope is expected to be bound to
+pub fn kek_unwrap(blob: &[u8], keks: Option<&KeyRing>, allow_unwrapped: bool, context: &[u8])
+    -> Result<(Vec<u8>, Option<u32>), ()> {
+    // Malformed blobs count as unwrapped, to be reported when they are opened
+    let envelope = envelope::parse(blob).ok();
+    let (envelope, kek_id) = match envelope.and_then(|envelope| envelope.header.kek_id.map(|kek_id| (envelope, kek_id))) {
+        Some(wrapped) => wrapped,
+        None if keks.is_none() || allow_unwrapped => return Ok((blob.to_vec(), None)),
+        None => return Err(())
+    };
+
+    let kek = keks.and_then(|keks| keks.get(kek_id)).ok_or(())?;
+    Ok((open_envelope(&envelope, kek.as_bytes(), context)?.into_vec(), Some(kek_id)))
+}
 
-    let mut password_cred: Credential = [0u8; CREDENTIAL_LEN];
-    pbkdf2_with_salt(password, &mut password_cred, &salt);
+/// Opens a parsed envelope with the key it was sealed with, returning the plaintext.
+/// A committed envelope is refused before decryption unless it commits to `key`.
+/// # Arguments
+///
+/// * `envelope` - The parsed envelope to open
+/// * `key` - The key the envelope was sealed with
+/// * `context` - The context the envelope is expected to be bound to
+fn open_envelope(envelope: &Envelope, key: &[u8], context: &[u8]) -> Result<SecretBytes, ()> {
+    let header = &envelope.header;
+    let nonce = aead::Nonce::try_assume_unique_for_key(&header.nonce).map_err(|_| ())?;
 
-    let nonce = aead::Nonce::try_assume_unique_for_key(&nonce).unwrap();
+    let committed_key;
+    let key = if header.is_committed() {
+        let (commitment, key) = commit_to_key(key)?;
+        constant_time::verify_slices_are_equal(&commitment, &header.commitment).map_err(|_| ())?;
+        committed_key = key;
+        committed_key.as_bytes()
+    } else {
+        key
+    };
 
-    let mut plaintext = ciphertext.clone();
-    let mut key: aead::OpeningKey<OneNonceSequence> = make_key(AEAD_ALG, &password_cred, nonce);
-    let result = key.open_within(aead::Aad::empty(), &mut plaintext, std::ops::RangeFrom::<usize> { start: 0 });
+    // The ciphertext is opened in place in a buffer that is wiped once dropped
+    let mut plaintext = SecretBytes::with_capacity(envelope.ciphertext.len());
+    plaintext.as_mut_vec().extend_from_slice(envelope.ciphertext);
+    let mut key: aead::OpeningKey<OneNonceSequence> = make_key(header.algorithm.ring(), key, nonce)?;
+    let plaintext_len = key.open_in_place(aead::Aad::from(envelope.associated_data(context)), plaintext.as_mut_vec())
+        .map_err(|_| ())?
+        .len();
 
-    if result.is_err() {
-        return Err(())
-    }
-    let plaintext_len = result.ok().unwrap().len();
-    let plaintext_slice = &plaintext[..plaintext_len];
-    let mut plaintext = Vec::<u8>::new();
-    plaintext.extend_from_slice(plaintext_slice);
+    plaintext.as_mut_vec().truncate(plaintext_len);
     Ok(plaintext)
 }
 
+/// Derives the commitment to a key along with the subkey a committed envelope is sealed with
+/// # Arguments
+///
+/// * `key` - The key to commit to
+fn commit_to_key(key: &[u8]) -> Result<(Vec<u8>, SecretBytes), ()> {
+    let commitment = hkdf_expand(key, COMMITMENT_INFO, COMMITMENT_LEN)?.as_bytes().to_vec();
+    let committed_key = hkdf_expand(key, COMMITTED_KEY_INFO, SUBKEY_LEN)?;
+    Ok((commitment, committed_key))
+}
+
 /// Represents a sequence of a single value to only be used once in the AEAD process
 struct OneNonceSequence(Option<aead::Nonce>);
 
@@ -112,4 +267,4 @@ impl aead::NonceSequence for OneNonceSequence {
     fn advance(&mut self) -> Result<aead::Nonce, error::Unspecified> {
         self.0.take().ok_or(error::Unspecified)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,301 @@
+//! The self-describing format of sealed blobs.
+//!
+//! An envelope is laid out as follows, with integers in big-endian:
+//!
+//! | Field | Size |
+//! | --- | --- |
+//! | Magic `JPWE` | 4 |
+//! | Format version | 1 |
+//! | AEAD algorithm id | 1 |
+//! | KDF id | 1 |
+//! | KDF parameters or key id | 0, 4 or 12 |
+//! | Salt length | 1 |
+//! | Salt | salt length |
+//! | Nonce length | 1 |
+//! | Nonce | nonce length |
+//! | Commitment length | 1, from version 3 |
+//! | Commitment | commitment length |
+//! | Ciphertext and tag | remainder |
+//!
+//! KDF ids are `0` for a raw key with no parameters, `1` for PBKDF2-HMAC-SHA256 followed by
+//! its iteration count, `2` for Argon2id followed by its memory cost in KiB, time cost
+//! and parallelism, and `3` for a server-side key-encryption key followed by its key id. Everything before the ciphertext is authenticated as associated data.
+//! KDF parameters beyond the limits in `hash` are refused while parsing, before anything is derived.
+//! Version 2 also authenticates context supplied by the caller, appended after the header,
+//! binding the blob to where it is stored. Version 1 blobs authenticate the header alone.
+//!
+//! From version 3 a blob may commit to its key. A committed blob records a 32 byte commitment
+//! derived from the key by HKDF-SHA256 and is sealed under a subkey derived the same way,
+//! so no ciphertext can be opened under more than one key. A commitment length of 0 marks
+//! a blob sealed directly under its key.
+//!
+//! Blobs without the magic are legacy blobs laid out as `ciphertext || salt || nonce`,
+//! sealed with AES-256-GCM under a key derived with a KDF known only to the caller.
+
+use super::{aead::AeadAlgorithm, hash::{Argon2Params, Kdf, SALT_LEN}};
+
+pub const MAGIC: &[u8; 4] = b"JPWE";
+pub const VERSION: u8 = 3;
+/// The oldest envelope version that can still be parsed
+pub const MIN_VERSION: u8 = 1;
+
+/// The length of the key commitment of a committed blob
+pub const COMMITMENT_LEN: usize = 32;
+
+const KDF_NONE: u8 = 0;
+const KDF_PBKDF2_SHA256: u8 = 1;
+const KDF_ARGON2ID: u8 = 2;
+const KDF_SERVER_KEY: u8 = 3;
+
+/// Represents the reasons a blob cannot be parsed
+#[derive(Debug, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The blob ends before a field it declares
+    Truncated,
+    /// The format version is newer or older than any that can be parsed
+    UnsupportedVersion(u8),
+    /// The AEAD algorithm id is unknown
+    UnknownAlgorithm(u8),
+    /// The KDF id is unknown
+    UnknownKdf(u8),
+    /// The KDF parameters exceed the limits a derivation is run with
+    KdfParamsOutOfBounds,
+    /// The nonce is not the length the AEAD algorithm uses
+    InvalidNonce,
+    /// The key commitment is neither empty nor `COMMITMENT_LEN` bytes
+    InvalidCommitment,
+}
+
+/// Represents everything needed to open a sealed blob besides the key or password
+pub struct Header {
+    /// The format version, 0 for legacy blobs
+    pub version: u8,
+    /// The AEAD algorithm the ciphertext was sealed with
+    pub algorithm: AeadAlgorithm,
+    /// The KDF used to derive the key from a password, `None` if sealed with a raw key
+    /// or if the blob is a legacy blob
+    pub kdf: Option<Kdf>,
+    /// The id of the server-side key-encryption key the blob was sealed with, if any
+    pub kek_id: Option<u32>,
+    /// The salt used with the KDF
+    pub salt: Vec<u8>,
+    /// The nonce used with the AEAD algorithm
+    pub nonce: Vec<u8>,
+    /// The commitment to the key the blob was sealed with, empty if the blob is not committed
+    pub commitment: Vec<u8>,
+}
+
+/// Represents a parsed sealed blob
+pub struct Envelope<'a> {
+    /// The parsed header of the blob
+    pub header: Header,
+    /// The encoded header, authenticated as associated data. Empty for legacy blobs
+    pub header_bytes: &'a [u8],
+    /// The ciphertext and tag
+    pub ciphertext: &'a [u8],
+}
+
+impl Envelope<'_> {
+    /// The associated data the ciphertext was sealed with, the header followed by the
+    /// caller's context from version 2 on. Legacy blobs were sealed without associated data
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - The context the blob is expected to be bound to
+    pub fn associated_data(&self, context: &[u8]) -> Vec<u8> {
+        associated_data(self.header.version, self.header_bytes, context)
+    }
+}
+
+/// Builds the associated data for a blob of a given version
+///
+/// # Arguments
+///
+/// * `version` - The format version of the blob
+/// * `header_bytes` - The encoded header of the blob
+/// * `context` - The context the blob is bound to
+pub fn associated_data(version: u8, header_bytes: &[u8], context: &[u8]) -> Vec<u8> {
+    let mut aad = header_bytes.to_vec();
+    if version >= 2 {
+        aad.extend(context);
+    }
+    aad
+}
+
+impl Header {
+    /// Creates a header for the current format version
+    ///
+    /// # Arguments
+    ///
+    /// * `algorithm` - The AEAD algorithm to seal with
+    /// * `kdf` - The KDF used to derive the key, `None` if sealing with a raw key
+    /// * `salt` - The salt used with the KDF
+    /// * `nonce` - The nonce used with the AEAD algorithm
+    pub fn new(algorithm: AeadAlgorithm, kdf: Option<Kdf>, salt: Vec<u8>, nonce: Vec<u8>) -> Self {
+        Header {
+            version: VERSION,
+            algorithm,
+            kdf,
+            kek_id: None,
+            salt,
+            nonce,
+            commitment: Vec::new(),
+        }
+    }
+
+    /// Whether the blob commits to the key it was sealed with
+    pub fn is_committed(&self) -> bool {
+        !self.commitment.is_empty()
+    }
+
+    /// Encodes this header, returning the bytes to prepend to the ciphertext
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.version);
+        bytes.push(self.algorithm.id());
+        match self.kdf {
+            None => match self.kek_id {
+                None => bytes.push(KDF_NONE),
+                Some(kek_id) => {
+                    bytes.push(KDF_SERVER_KEY);
+                    bytes.extend(&kek_id.to_be_bytes());
+                }
+            },
+            Some(Kdf::Pbkdf2Sha256 { iterations }) => {
+                bytes.push(KDF_PBKDF2_SHA256);
+                bytes.extend(&iterations.to_be_bytes());
+            },
+            Some(Kdf::Argon2id(params)) => {
+                bytes.push(KDF_ARGON2ID);
+                bytes.extend(&params.memory_kib.to_be_bytes());
+                bytes.extend(&params.iterations.to_be_bytes());
+                bytes.extend(&params.parallelism.to_be_bytes());
+            }
+        }
+        bytes.push(self.salt.len() as u8);
+        bytes.extend(&self.salt);
+        bytes.push(self.nonce.len() as u8);
+        bytes.extend(&self.nonce);
+        if self.version >= 3 {
+            bytes.push(self.commitment.len() as u8);
+            bytes.extend(&self.commitment);
+        }
+        bytes
+    }
+}
+
+/// Parses a sealed blob, either in the current envelope format or the legacy layout.
+/// Never panics, whatever the blob holds
+///
+/// # Arguments
+///
+/// * `blob` - The sealed blob to parse
+pub fn parse(blob: &[u8]) -> Result<Envelope<'_>, EnvelopeError> {
+    if !blob.starts_with(MAGIC) {
+        return parse_legacy(blob);
+    }
+
+    let mut reader = Reader { bytes: blob, position: MAGIC.len() };
+    let version = reader.u8()?;
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(EnvelopeError::UnsupportedVersion(version));
+    }
+    let algorithm_id = reader.u8()?;
+    let algorithm = AeadAlgorithm::from_id(algorithm_id).map_err(|_| EnvelopeError::UnknownAlgorithm(algorithm_id))?;
+    let mut kek_id = None;
+    let kdf = match reader.u8()? {
+        KDF_NONE => None,
+        KDF_SERVER_KEY => {
+            kek_id = Some(reader.u32()?);
+            None
+        },
+        KDF_PBKDF2_SHA256 => Some(Kdf::Pbkdf2Sha256 { iterations: reader.u32()? }),
+        KDF_ARGON2ID => Some(Kdf::Argon2id(Argon2Params {
+            memory_kib: reader.u32()?,
+            iterations: reader.u32()?,
+            parallelism: reader.u32()?,
+        })),
+        kdf_id => return Err(EnvelopeError::UnknownKdf(kdf_id))
+    };
+    if kdf.is_some_and(|kdf| !kdf.is_within_limits()) {
+        return Err(EnvelopeError::KdfParamsOutOfBounds);
+    }
+    let salt_len = reader.u8()? as usize;
+    let salt = reader.take(salt_len)?.to_vec();
+    let nonce_len = reader.u8()? as usize;
+    let nonce = reader.take(nonce_len)?.to_vec();
+    if nonce.len() != algorithm.nonce_len() {
+        return Err(EnvelopeError::InvalidNonce);
+    }
+    let commitment = if version >= 3 {
+        let commitment_len = reader.u8()? as usize;
+        if commitment_len != 0 && commitment_len != COMMITMENT_LEN {
+            return Err(EnvelopeError::InvalidCommitment);
+        }
+        reader.take(commitment_len)?.to_vec()
+    } else {
+        Vec::new()
+    };
+
+    let (header_bytes, ciphertext) = blob.split_at(reader.position);
+    Ok(Envelope {
+        header: Header { version, algorithm, kdf, kek_id, salt, nonce, commitment },
+        header_bytes,
+        ciphertext,
+    })
+}
+
+/// Breaks down a legacy `ciphertext || salt || nonce` blob
+///
+/// # Arguments
+///
+/// * `blob` - The legacy blob to parse
+fn parse_legacy(blob: &[u8]) -> Result<Envelope<'_>, EnvelopeError> {
+    let algorithm = AeadAlgorithm::Aes256Gcm;
+    let nonce_len = algorithm.nonce_len();
+    let ciphertext_len = blob.len().checked_sub(SALT_LEN + nonce_len).ok_or(EnvelopeError::Truncated)?;
+
+    let (ciphertext, salt_nonce) = blob.split_at(ciphertext_len);
+    let (salt, nonce) = salt_nonce.split_at(SALT_LEN);
+    Ok(Envelope {
+        header: Header {
+            version: 0,
+            algorithm,
+            kdf: None,
+            kek_id: None,
+            salt: salt.to_vec(),
+            nonce: nonce.to_vec(),
+            commitment: Vec::new(),
+        },
+        header_bytes: &[],
+        ciphertext,
+    })
+}
+
+/// Reads fields from the front of a blob without reading past its end
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Reads the next `len` bytes
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EnvelopeError> {
+        let end = self.position.checked_add(len).ok_or(EnvelopeError::Truncated)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(EnvelopeError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// Reads the next byte
+    fn u8(&mut self) -> Result<u8, EnvelopeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads the next four bytes as a big-endian integer
+    fn u32(&mut self) -> Result<u32, EnvelopeError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+}
@@ -1,49 +1,348 @@
-use ring::{pbkdf2, digest::{self, digest}};
-use super::{rand::generate_rand_vec};
-use std::{num::NonZeroU32};
+use ring::{pbkdf2, hkdf, constant_time, digest::{self, digest}};
+use argon2::{Argon2, Algorithm, Version, Params};
+use base64ct::{Base64Unpadded, Encoding};
+use super::{rand::{generate_rand_vec, Rng}, secret::SecretBytes};
+use std::{num::NonZeroU32, time::{Duration, Instant}};
 
-const PBKDF2_ITER: u32 = 20_000;
+/// The iteration count used by rows written before Argon2id was introduced
+pub const LEGACY_PBKDF2_ITER: u32 = 20_000;
 pub const SALT_LEN: usize = 32;
+pub const SUBKEY_LEN: usize = 32;
+const MASTER_KEY_LEN: usize = 32;
+
+/// The most memory in KiB an Argon2id derivation may use. Parameters are read back from stored rows
+/// and blobs, so anything above these limits is refused rather than run
+pub const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+/// The most passes over the memory an Argon2id derivation may make
+pub const MAX_ITERATIONS: u32 = 64;
+/// The most lanes an Argon2id derivation may use
+pub const MAX_PARALLELISM: u32 = 16;
+/// The most iterations a PBKDF2 derivation may run
+pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// The number of times each candidate is timed during calibration, keeping the fastest
+const CALIBRATION_RUNS: u32 = 3;
+
+const AUTH_INFO: &[u8] = b"jpassword authentication";
+const ENCRYPTION_INFO: &[u8] = b"jpassword encryption";
 
 static PBKDF2_ALG: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
 static HASH_ALG: &digest::Algorithm = &digest::SHA256;
 
-/// Creates a password based key using PBKDF2, returning the random salt used in its creation
+/// The cost parameters of an Argon2id derivation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Argon2Params {
+    /// The memory cost in KiB
+    pub memory_kib: u32,
+    /// The number of passes over the memory
+    pub iterations: u32,
+    /// The number of lanes
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    /// The OWASP recommended minimum for Argon2id
+    fn default() -> Self {
+        Argon2Params {
+            memory_kib: 19_456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// A key derivation function along with the parameters it is run with
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kdf {
+    /// PBKDF2-HMAC-SHA256, only kept to verify rows written before Argon2id
+    Pbkdf2Sha256 { iterations: u32 },
+    /// Argon2id, the default for all new hashes and keys
+    Argon2id(Argon2Params),
+}
+
+/// Represents the reasons a PHC string cannot be parsed
+#[derive(Debug, PartialEq, Eq)]
+pub enum PhcError {
+    /// The string is not a PHC string produced by `to_phc`
+    Malformed,
+    /// The KDF parameters exceed `MAX_MEMORY_KIB`, `MAX_ITERATIONS`, `MAX_PARALLELISM` or `MAX_PBKDF2_ITERATIONS`
+    ParamsOutOfBounds,
+}
+
+impl Kdf {
+    /// The KDF used by every row written before Argon2id was introduced
+    pub fn legacy() -> Self {
+        Kdf::Pbkdf2Sha256 { iterations: LEGACY_PBKDF2_ITER }
+    }
+
+    /// Whether the parameters are within the limits a derivation is run with, so a corrupt row
+    /// or blob cannot exhaust the memory of the process or hold a worker for hours
+    pub fn is_within_limits(&self) -> bool {
+        match self {
+            Kdf::Pbkdf2Sha256 { iterations } => *iterations <= MAX_PBKDF2_ITERATIONS,
+            Kdf::Argon2id(params) => params.memory_kib <= MAX_MEMORY_KIB && params.iterations <= MAX_ITERATIONS
+                && params.parallelism <= MAX_PARALLELISM,
+        }
+    }
+
+    /// Whether hashes made with this KDF should be rehashed with `other`, being weaker in
+    /// every parameter that was lowered. Hashes are never rehashed with weaker parameters,
+    /// so a calibration that lands slightly lower than the last one leaves them as they are
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The KDF new hashes are made with
+    pub fn is_weaker_than(&self, other: &Kdf) -> bool {
+        match (self, other) {
+            (Kdf::Pbkdf2Sha256 { iterations }, Kdf::Pbkdf2Sha256 { iterations: other }) => iterations < other,
+            (Kdf::Pbkdf2Sha256 { .. }, Kdf::Argon2id(_)) => true,
+            (Kdf::Argon2id(_), Kdf::Pbkdf2Sha256 { .. }) => false,
+            (Kdf::Argon2id(params), Kdf::Argon2id(other)) =>
+                params.memory_kib < other.memory_kib || params.iterations < other.iterations,
+        }
+    }
+
+    /// Derives a key from a password and salt, filling `out`
+    ///
+    /// # Arguments
+    ///
+    /// * `password` - The array of bytes used as a password
+    /// * `salt` - The salt used for the derivation
+    /// * `out` - The mutable array of bytes to write the key to
+    pub fn derive(&self, password: &[u8], salt: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        if !self.is_within_limits() {
+            return Err(());
+        }
+        match self {
+            Kdf::Pbkdf2Sha256 { iterations } => {
+                let iterations = NonZeroU32::new(*iterations).ok_or(())?;
+                pbkdf2::derive(PBKDF2_ALG, iterations, salt, password, out);
+                Ok(())
+            },
+            Kdf::Argon2id(params) => {
+                let params = Params::new(params.memory_kib, params.iterations,
+                    params.parallelism, Some(out.len())).map_err(|_| ())?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password, salt, out)
+                    .map_err(|_| ())
+            }
+        }
+    }
+}
+
+/// Benchmarks Argon2id on this machine, returning parameters whose derivation takes about
+/// `target` along with how long they took. The memory cost and parallelism are kept from
+/// `floor` and the time cost is raised to meet the target, never going below `floor`
+/// nor above `MAX_ITERATIONS`
+///
 /// # Arguments
 ///
-/// * `password` - The array of bytes used as a password
-/// * `in_out` - The mutable array of bytes to write the key to
-pub fn pbkdf2_rand_salt(password: &[u8], in_out: &mut[u8]) -> Vec<u8> {
-    let salt = generate_rand_vec(SALT_LEN).unwrap();
+/// * `target` - How long a derivation should take
+/// * `floor` - The weakest parameters to return, whose memory cost and parallelism are kept
+pub fn calibrate(target: Duration, floor: Argon2Params) -> Result<(Argon2Params, Duration), ()> {
+    let elapsed = time_derivation(floor)?;
+    let scale = target.as_secs_f64() / elapsed.as_secs_f64();
+    let iterations = (floor.iterations as f64 * scale) as u32;
+
+    let params = Argon2Params { iterations: iterations.min(MAX_ITERATIONS).max(floor.iterations), ..floor };
+    if params == floor {
+        return Ok((params, elapsed));
+    }
+    Ok((params, time_derivation(params)?))
+}
 
-    pbkdf2_with_salt(password, in_out, &salt);
+/// Times the fastest of several Argon2id derivations with a set of parameters
+///
+/// # Arguments
+///
+/// * `params` - The parameters to time
+fn time_derivation(params: Argon2Params) -> Result<Duration, ()> {
+    let kdf = Kdf::Argon2id(params);
+    let mut out = SecretBytes::zeroed(MASTER_KEY_LEN);
+    let mut fastest = Duration::MAX;
+    for _ in 0..CALIBRATION_RUNS {
+        let start = Instant::now();
+        kdf.derive(b"calibration password", &[0u8; SALT_LEN], out.as_mut_bytes())?;
+        fastest = fastest.min(start.elapsed());
+    }
+    Ok(fastest)
+}
 
-    salt
+/// A password hash along with the KDF, parameters and salt used to produce it.
+/// Encodes to and from a PHC string such as
+/// `$argon2id$v=19$m=19456,t=2,p=1,split=1$<salt>$<hash>`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PasswordHash {
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+    pub hash: Vec<u8>,
+    /// If set, the hash is the authentication subkey split from a master key by HKDF
+    /// rather than the output of the KDF itself
+    pub split: bool,
 }
 
-/// Creates a password based key using a given salt
+impl PasswordHash {
+    /// Encodes this hash as a PHC string
+    pub fn to_phc(&self) -> String {
+        let id_and_params = match self.kdf {
+            Kdf::Pbkdf2Sha256 { iterations } =>
+                format!("$pbkdf2-sha256$i={}", iterations),
+            Kdf::Argon2id(params) =>
+                format!("$argon2id$v=19$m={},t={},p={}",
+                    params.memory_kib, params.iterations, params.parallelism),
+        };
+        let split = if self.split { ",split=1" } else { "" };
+        format!("{}{}${}${}", id_and_params, split,
+            Base64Unpadded::encode_string(&self.salt),
+            Base64Unpadded::encode_string(&self.hash))
+    }
+
+    /// Parses a PHC string produced by `to_phc`, refusing parameters beyond the limits
+    /// a derivation is run with
+    ///
+    /// # Arguments
+    ///
+    /// * `phc` - The PHC string to parse
+    pub fn from_phc(phc: &str) -> Result<Self, PhcError> {
+        let fields: Vec<&str> = phc.split('$').collect();
+        let (kdf, params, salt, hash) = match fields.as_slice() {
+            ["", "pbkdf2-sha256", params, salt, hash] => {
+                let iterations = parse_param(params, "i").map_err(|_| PhcError::Malformed)?;
+                (Kdf::Pbkdf2Sha256 { iterations }, params, salt, hash)
+            },
+            ["", "argon2id", "v=19", params, salt, hash] => {
+                let argon2_params = Argon2Params {
+                    memory_kib: parse_param(params, "m").map_err(|_| PhcError::Malformed)?,
+                    iterations: parse_param(params, "t").map_err(|_| PhcError::Malformed)?,
+                    parallelism: parse_param(params, "p").map_err(|_| PhcError::Malformed)?,
+                };
+                (Kdf::Argon2id(argon2_params), params, salt, hash)
+            },
+            _ => return Err(PhcError::Malformed)
+        };
+        if !kdf.is_within_limits() {
+            return Err(PhcError::ParamsOutOfBounds);
+        }
+
+        Ok(PasswordHash {
+            kdf,
+            salt: Base64Unpadded::decode_vec(salt).map_err(|_| PhcError::Malformed)?,
+            hash: Base64Unpadded::decode_vec(hash).map_err(|_| PhcError::Malformed)?,
+            split: parse_param(params, "split") == Ok(1),
+        })
+    }
+}
+
+/// Finds a `key=value` pair in a comma separated PHC parameter list
+fn parse_param(params: &str, key: &str) -> Result<u32, ()> {
+    params.split(',')
+        .filter_map(|param| param.split_once('='))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, value)| value.parse().ok())
+        .ok_or(())
+}
+
+/// A key derived from a password once per request, from which the
+/// authentication and encryption subkeys are split by HKDF
+pub struct MasterKey(SecretBytes);
+
+impl MasterKey {
+    /// Derives the master key from a password and salt
+    ///
+    /// # Arguments
+    ///
+    /// * `kdf` - The KDF and parameters to derive the key with
+    /// * `password` - The array of bytes used as a password
+    /// * `salt` - The salt used for the derivation
+    pub fn derive(kdf: &Kdf, password: &[u8], salt: &[u8]) -> Result<Self, ()> {
+        let mut key = SecretBytes::zeroed(MASTER_KEY_LEN);
+        kdf.derive(password, salt, key.as_mut_bytes())?;
+        Ok(MasterKey(key))
+    }
+
+    /// The subkey stored to verify the password
+    pub fn auth_key(&self) -> Result<SecretBytes, ()> {
+        hkdf_expand(self.0.as_bytes(), AUTH_INFO, SUBKEY_LEN)
+    }
+
+    /// The subkey that wraps the vault key
+    pub fn encryption_key(&self) -> Result<SecretBytes, ()> {
+        hkdf_expand(self.0.as_bytes(), ENCRYPTION_INFO, SUBKEY_LEN)
+    }
+}
+
+/// The output length of an HKDF expansion
+struct HkdfLen(usize);
+
+impl hkdf::KeyType for HkdfLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Expands a key into a subkey for a purpose using HKDF-SHA256
+///
+/// # Arguments
+///
+/// * `key` - The key to expand
+/// * `info` - The purpose of the subkey
+/// * `len` - The length of the subkey
+pub fn hkdf_expand(key: &[u8], info: &[u8], len: usize) -> Result<SecretBytes, ()> {
+    let mut out = SecretBytes::zeroed(len);
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(key)
+        .expand(&[info], HkdfLen(len)).map_err(|_| ())?
+        .fill(out.as_mut_bytes()).map_err(|_| ())?;
+    Ok(out)
+}
+
+/// Derives a master key from a password with a random salt,
+/// returning it along with the password hash to store for it
 /// # Arguments
 ///
+/// * `rng` - The source of the salt
+/// * `kdf` - The KDF and parameters to derive the master key with
 /// * `password` - The array of bytes used as a password
-/// * `in_out` - The mutable array of bytes to write the key to
-/// * `salt` - The salt used for the PBKDF2 function
-pub fn pbkdf2_with_salt(password: &[u8], in_out: &mut[u8], salt: &[u8]) {
-    pbkdf2::derive(PBKDF2_ALG, NonZeroU32::new(PBKDF2_ITER).unwrap(), &salt,
-    password, in_out);
+pub fn new_master_key(rng: &dyn Rng, kdf: &Kdf, password: &[u8]) -> Result<(MasterKey, PasswordHash), ()> {
+    let salt = generate_rand_vec(rng, SALT_LEN)?;
+    let master_key = MasterKey::derive(kdf, password, &salt)?;
+    let hash = master_key.auth_key()?.as_bytes().to_vec();
+
+    Ok((master_key, PasswordHash { kdf: *kdf, salt, hash, split: true }))
 }
 
-/// Verifies a password based key using a password and salt
+/// Generates a password hash no known password matches, which cannot be told apart from a real one,
+/// returning it along with a random key standing in for its master key's encryption subkey
+///
+/// # Arguments
+///
+/// * `rng` - The source of the salt, hash and key
+/// * `kdf` - The KDF and parameters the hash claims to be made with
+pub fn unmatched_password_hash(rng: &dyn Rng, kdf: &Kdf) -> Result<(SecretBytes, PasswordHash), ()> {
+    let salt = generate_rand_vec(rng, SALT_LEN)?;
+    let hash = generate_rand_vec(rng, SUBKEY_LEN)?;
+    let encryption_key = SecretBytes::random(rng, SUBKEY_LEN)?;
+
+    Ok((encryption_key, PasswordHash { kdf: *kdf, salt, hash, split: true }))
+}
+
+/// Verifies a password against a stored password hash, running the KDF once.
+/// Returns the master key if the hash is split, or `None` if the hash predates
+/// master keys and the password must be run through the KDF again to unwrap anything.
 /// # Arguments
 ///
 /// * `password` - The array of bytes used as a password
-/// * `in` - The key to test the password on salt on
-/// * `salt` - The salt used for the PBKDF2 function
-pub fn pbkdf2_verify(password: &[u8], _in: &[u8], salt: &[u8]) -> Result<(), ()> {
-    let result = pbkdf2::verify(PBKDF2_ALG, NonZeroU32::new(PBKDF2_ITER).unwrap(), salt, password, _in);
-    if result.is_err() {
-        return Err(())
+/// * `stored` - The stored hash to test the password on
+pub fn verify_password(password: &[u8], stored: &PasswordHash) -> Result<Option<MasterKey>, ()> {
+    if stored.split {
+        let master_key = MasterKey::derive(&stored.kdf, password, &stored.salt)?;
+        constant_time::verify_slices_are_equal(master_key.auth_key()?.as_bytes(), &stored.hash).map_err(|_| ())?;
+        return Ok(Some(master_key));
     }
-    Ok(())
+
+    let mut hash = SecretBytes::zeroed(stored.hash.len());
+    stored.kdf.derive(password, &stored.salt, hash.as_mut_bytes())?;
+    constant_time::verify_slices_are_equal(hash.as_bytes(), &stored.hash).map_err(|_| ())?;
+    Ok(None)
 }
 
 /// Hashes an array of bytes, returning a vector of bytes as the hash
@@ -52,4 +351,4 @@ pub fn pbkdf2_verify(password: &[u8], _in: &[u8], salt: &[u8]) -> Result<(), ()>
 /// * `to_hash` - The array of bytes to hash
 pub fn hash(to_hash: &[u8]) -> Vec<u8> {
     digest(HASH_ALG, to_hash).as_ref().to_vec()
-}
\ No newline at end of file
+}
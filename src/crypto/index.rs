@@ -0,0 +1,57 @@
+//! The keyed index users are looked up by.
+//!
+//! A username is indexed as `hex(HMAC-SHA256(key, username))` under a key from the index key
+//! file, so a copy of the database alone cannot confirm whether a username is registered.
+//! Rows indexed before keys were introduced are indexed by `hex(SHA-256(username))` with no key id.
+
+use ring::hmac;
+use super::{hash::hash, keyring::{KeyRing, RingKey}};
+
+/// The index of a username as stored in the `hash` and `index_key` columns
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UsernameIndex {
+    /// The hex encoded index
+    pub hash: String,
+    /// The id of the key the username is indexed under, `None` for the legacy unkeyed index
+    pub key_id: Option<u32>,
+}
+
+/// Indexes a username under a key
+///
+/// # Arguments
+///
+/// * `key` - The index key
+/// * `username` - The username to index
+pub fn index(key: &RingKey, username: &str) -> UsernameIndex {
+    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    UsernameIndex {
+        hash: hex::encode(hmac::sign(&hmac_key, username.as_bytes())),
+        key_id: Some(key.id),
+    }
+}
+
+/// The index of a username in rows written before the index was keyed
+///
+/// # Arguments
+///
+/// * `username` - The username to index
+pub fn legacy_index(username: &str) -> UsernameIndex {
+    UsernameIndex {
+        hash: hex::encode(hash(username.as_bytes())),
+        key_id: None,
+    }
+}
+
+/// Every index a username may be stored under, the current index first,
+/// followed by older keys' indexes and the legacy index
+///
+/// # Arguments
+///
+/// * `keys` - The index keys
+/// * `username` - The username to index
+pub fn candidates(keys: &KeyRing, username: &str) -> Vec<UsernameIndex> {
+    keys.keys().iter().rev()
+        .map(|key| index(key, username))
+        .chain(std::iter::once(legacy_index(username)))
+        .collect()
+}
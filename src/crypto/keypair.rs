@@ -0,0 +1,112 @@
+//! X25519 keypairs that let anyone wrap a secret to a User without any of the User's secrets.
+//!
+//! A secret is wrapped to a public key by agreeing on a shared secret with a fresh ephemeral key,
+//! from which the wrapping key is derived by HKDF over the shared secret and both public keys.
+//! The wrapped secret is the ephemeral public key followed by a committing envelope.
+
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+use super::{aead::{aead_open_with_key, aead_seal_with_key, AeadAlgorithm}, hash::{hkdf_expand, SUBKEY_LEN},
+    rand::Rng, secret::SecretBytes};
+
+/// The length of an X25519 public or private key
+pub const KEY_LEN: usize = 32;
+
+const WRAP_INFO: &[u8] = b"jpassword public key wrap";
+
+/// Generates a new keypair, returning the private key along with the public key
+///
+/// # Arguments
+///
+/// * `rng` - The source of the private key
+pub fn new_keypair(rng: &dyn Rng) -> Result<(SecretBytes, Vec<u8>), ()> {
+    let private_key = SecretBytes::random(rng, KEY_LEN)?;
+    let public_key = PublicKey::from(&static_secret(private_key.as_bytes())?).as_bytes().to_vec();
+    Ok((private_key, public_key))
+}
+
+/// Wraps a secret so only the holder of the private key of `public_key` can open it
+///
+/// # Arguments
+///
+/// * `rng` - The source of the ephemeral key and the nonce
+/// * `public_key` - The public key of the recipient
+/// * `secret` - The secret to wrap
+/// * `algorithm` - The AEAD algorithm to seal the secret with
+/// * `context` - The context to bind the wrapped secret to
+pub fn wrap_to(rng: &dyn Rng, public_key: &[u8], secret: &[u8], algorithm: AeadAlgorithm, context: &[u8]) -> Result<Vec<u8>, ()> {
+    let recipient = public_key_from(public_key)?;
+    let ephemeral_private = SecretBytes::random(rng, KEY_LEN)?;
+    let ephemeral_private = static_secret(ephemeral_private.as_bytes())?;
+    let ephemeral_public = PublicKey::from(&ephemeral_private);
+
+    let key = wrapping_key(&ephemeral_private, &recipient, ephemeral_public.as_bytes(), recipient.as_bytes())?;
+    let mut wrapped = ephemeral_public.as_bytes().to_vec();
+    wrapped.extend(aead_seal_with_key(rng, secret, key.as_bytes(), algorithm, true, context)?);
+    Ok(wrapped)
+}
+
+/// Opens a secret wrapped by `wrap_to`, returning the secret
+///
+/// # Arguments
+///
+/// * `private_key` - The private key of the recipient
+/// * `wrapped` - The wrapped secret
+/// * `context` - The context the wrapped secret is expected to be bound to
+pub fn open_from(private_key: &[u8], wrapped: &[u8], context: &[u8]) -> Result<SecretBytes, ()> {
+    if wrapped.len() < KEY_LEN {
+        return Err(());
+    }
+    let (ephemeral_public, sealed) = wrapped.split_at(KEY_LEN);
+    let ephemeral_public = public_key_from(ephemeral_public)?;
+    let private_key = static_secret(private_key)?;
+    let public_key = PublicKey::from(&private_key);
+
+    let key = wrapping_key(&private_key, &ephemeral_public, ephemeral_public.as_bytes(), public_key.as_bytes())?;
+    aead_open_with_key(sealed, key.as_bytes(), context)
+}
+
+/// Derives the key a secret is wrapped with from the shared secret of two keys
+///
+/// # Arguments
+///
+/// * `private_key` - One party's private key
+/// * `public_key` - The other party's public key
+/// * `ephemeral_public` - The ephemeral public key of the wrap
+/// * `recipient_public` - The public key of the recipient
+fn wrapping_key(private_key: &StaticSecret, public_key: &PublicKey, ephemeral_public: &[u8],
+    recipient_public: &[u8]) -> Result<SecretBytes, ()> {
+    let shared = private_key.diffie_hellman(public_key);
+    // A low order public key agrees on an all-zero secret whatever the private key
+    if shared.as_bytes().iter().all(|byte| *byte == 0) {
+        return Err(());
+    }
+    let mut input = SecretBytes::with_capacity(3 * KEY_LEN);
+    input.as_mut_vec().extend_from_slice(shared.as_bytes());
+    input.as_mut_vec().extend_from_slice(ephemeral_public);
+    input.as_mut_vec().extend_from_slice(recipient_public);
+    hkdf_expand(input.as_bytes(), WRAP_INFO, SUBKEY_LEN)
+}
+
+/// Reads a private key
+fn static_secret(private_key: &[u8]) -> Result<StaticSecret, ()> {
+    let mut bytes = [0u8; KEY_LEN];
+    if private_key.len() != KEY_LEN {
+        return Err(());
+    }
+    bytes.copy_from_slice(private_key);
+    // StaticSecret zeroizes its own copy on drop
+    let secret = StaticSecret::from(bytes);
+    bytes.zeroize();
+    Ok(secret)
+}
+
+/// Reads a public key
+fn public_key_from(public_key: &[u8]) -> Result<PublicKey, ()> {
+    let mut bytes = [0u8; KEY_LEN];
+    if public_key.len() != KEY_LEN {
+        return Err(());
+    }
+    bytes.copy_from_slice(public_key);
+    Ok(PublicKey::from(bytes))
+}
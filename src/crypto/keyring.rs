@@ -0,0 +1,162 @@
+//! Server-side keys held in a key file outside the database.
+//!
+//! A key file holds one `<id>:<hex key>` line per key, the highest id being the current key.
+//! Older keys are kept so rows written under them can still be read until they are rewritten.
+
+use std::{fs, io::{self, Write}, path::Path};
+use zeroize::Zeroizing;
+use super::{rand::Rng, secret::SecretBytes};
+
+pub const RING_KEY_LEN: usize = 32;
+
+/// A key held in a key file
+pub struct RingKey {
+    /// The id stored alongside everything written under this key
+    pub id: u32,
+    key: SecretBytes,
+}
+
+impl RingKey {
+    /// The key
+    pub fn as_bytes(&self) -> &[u8] {
+        self.key.as_bytes()
+    }
+}
+
+/// The set of keys held in a key file
+pub struct KeyRing {
+    /// The keys in order of id, the last being the current key
+    keys: Vec<RingKey>,
+}
+
+impl KeyRing {
+    /// Reads the keys from a key file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the key file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        // The hex encoded keys are wiped once decoded
+        let contents = Zeroizing::new(fs::read_to_string(path)?);
+
+        let mut keys = Vec::new();
+        for line in contents.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (id, key) = line.split_once(':').ok_or_else(|| invalid_key_file("a line is not `<id>:<hex key>`"))?;
+            let id = id.parse().map_err(|_| invalid_key_file("a key id is not a number"))?;
+            let mut key_bytes = SecretBytes::zeroed(RING_KEY_LEN);
+            hex::decode_to_slice(key, key_bytes.as_mut_bytes())
+                .map_err(|_| invalid_key_file("a key is not 32 hex encoded bytes"))?;
+            keys.push(RingKey { id, key: key_bytes });
+        }
+        keys.sort_by_key(|key| key.id);
+        if keys.is_empty() {
+            return Err(invalid_key_file("it holds no keys"));
+        }
+        Ok(KeyRing { keys })
+    }
+
+    /// Reads the keys from a key file, creating the file with a new key if it does not exist
+    ///
+    /// # Arguments
+    ///
+    /// * `rng` - The source of a new key
+    /// * `path` - The path of the key file
+    pub fn load_or_create(rng: &dyn Rng, path: &Path) -> io::Result<Self> {
+        match Self::load(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::create(rng, path),
+            loaded => loaded,
+        }
+    }
+
+    /// Creates a key file holding a new key, refusing to replace a key file that exists
+    ///
+    /// # Arguments
+    ///
+    /// * `rng` - The source of the key
+    /// * `path` - The path of the key file
+    pub fn create(rng: &dyn Rng, path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())));
+        }
+        let mut keys = KeyRing { keys: Vec::new() };
+        keys.rotate(rng)?;
+        keys.save(path)?;
+        Ok(keys)
+    }
+
+    /// Writes the keys to a key file readable only by its owner, replacing it in one step
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the key file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temp_path = path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&temp_path)?;
+        for key in &self.keys {
+            writeln!(file, "{}:{}", key.id, hex::encode(key.key.as_bytes()))?;
+        }
+        file.sync_all()?;
+        fs::rename(temp_path, path)
+    }
+
+    /// The key new rows are indexed under
+    pub fn current(&self) -> &RingKey {
+        self.keys.last().unwrap()
+    }
+
+    /// The key with an id, if it is still held
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of the key
+    pub fn get(&self, id: u32) -> Option<&RingKey> {
+        self.keys.iter().find(|key| key.id == id)
+    }
+
+    /// Adds a new random key that becomes the current key, returning its id
+    ///
+    /// # Arguments
+    ///
+    /// * `rng` - The source of the key
+    pub fn rotate(&mut self, rng: &dyn Rng) -> io::Result<u32> {
+        let id = self.keys.last().map_or(1, |key| key.id + 1);
+        let key = SecretBytes::random(rng, RING_KEY_LEN)
+            .map_err(|_| io::Error::other("could not generate a key"))?;
+        self.keys.push(RingKey { id, key });
+        Ok(id)
+    }
+
+    /// Removes the older keys `keep` returns false for. The current key is always kept
+    ///
+    /// # Arguments
+    ///
+    /// * `keep` - Whether to keep the key with an id
+    pub fn retain(&mut self, keep: impl Fn(u32) -> bool) {
+        let current = self.current().id;
+        self.keys.retain(|key| key.id == current || keep(key.id));
+    }
+
+    /// Every key, in order of id
+    pub fn keys(&self) -> &[RingKey] {
+        &self.keys
+    }
+
+    /// The ids of every key, in order
+    pub fn ids(&self) -> Vec<u32> {
+        self.keys.iter().map(|key| key.id).collect()
+    }
+}
+
+/// An error describing why a key file could not be read
+///
+/// # Arguments
+///
+/// * `reason` - Why the key file is invalid
+fn invalid_key_file(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid key file, {}", reason))
+}
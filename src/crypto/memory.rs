@@ -0,0 +1,186 @@
+use std::{collections::BTreeMap, io, sync::{Mutex, atomic::{AtomicBool, Ordering}}};
+
+/// A rough upper bound on the bytes of secret buffers a worker thread holds at once,
+/// used to warn when `RLIMIT_MEMLOCK` is too low to lock them all
+pub const LOCKED_BYTES_PER_WORKER: u64 = 64 * 1024;
+
+/// Whether secret buffers are locked into memory
+static LOCK_MEMORY: AtomicBool = AtomicBool::new(false);
+/// Whether a failure to lock memory has been reported
+static LOCK_FAILURE_REPORTED: AtomicBool = AtomicBool::new(false);
+/// The number of live secret buffers on each locked page. Pages are locked and unlocked
+/// as a whole and locks do not nest, so a page is only unlocked once no buffer is left on it
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Locks every secret buffer allocated from now on into memory, keeping it out of swap
+pub fn enable_locking() {
+    LOCK_MEMORY.store(true, Ordering::SeqCst);
+}
+
+/// Locks the pages of a secret buffer into memory if locking is enabled.
+/// A failure is reported once rather than failing the request.
+///
+/// # Arguments
+///
+/// * `ptr` - The start of the buffer
+/// * `len` - The allocated length of the buffer
+pub fn lock(ptr: *const u8, len: usize) {
+    if len == 0 || !LOCK_MEMORY.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let mut locked_pages = LOCKED_PAGES.lock().unwrap();
+    for page in pages(ptr, len) {
+        let count = locked_pages.entry(page).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            if let Err(err) = sys::lock_page(page, page_size()) {
+                report_lock_failure(err);
+            }
+        }
+    }
+}
+
+/// Unlocks the pages of a secret buffer that no other secret buffer is on.
+/// The buffer must have been wiped first.
+///
+/// # Arguments
+///
+/// * `ptr` - The start of the buffer
+/// * `len` - The allocated length of the buffer
+pub fn unlock(ptr: *const u8, len: usize) {
+    if len == 0 || !LOCK_MEMORY.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let mut locked_pages = LOCKED_PAGES.lock().unwrap();
+    for page in pages(ptr, len) {
+        if let Some(count) = locked_pages.get_mut(&page) {
+            *count -= 1;
+            if *count == 0 {
+                locked_pages.remove(&page);
+                let _ = sys::unlock_page(page, page_size());
+            }
+        }
+    }
+}
+
+/// Stops the process from writing core dumps, which would hold any secret in memory
+pub fn disable_core_dumps() -> io::Result<()> {
+    sys::disable_core_dumps()
+}
+
+/// The number of bytes the process may lock into memory, or `None` if unlimited
+pub fn memlock_limit() -> io::Result<Option<u64>> {
+    sys::memlock_limit()
+}
+
+/// The start addresses of the pages a buffer lies on
+///
+/// # Arguments
+///
+/// * `ptr` - The start of the buffer
+/// * `len` - The allocated length of the buffer
+fn pages(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
+    let page_size = page_size();
+    let first = ptr as usize / page_size * page_size;
+    let end = ptr as usize + len;
+    (first..end).step_by(page_size)
+}
+
+/// The size of a page of memory
+fn page_size() -> usize {
+    sys::page_size()
+}
+
+/// Reports the first failure to lock memory along with the limit that likely caused it
+///
+/// # Arguments
+///
+/// * `err` - The error returned by the failed lock
+fn report_lock_failure(err: io::Error) {
+    if LOCK_FAILURE_REPORTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let limit = match memlock_limit() {
+        Ok(Some(limit)) => format!("{} bytes", limit),
+        Ok(None) => "unlimited".to_string(),
+        Err(_) => "unknown".to_string(),
+    };
+    eprintln!("Warning: could not lock secret memory ({}), keys and vaults may be swapped to disk. \
+        RLIMIT_MEMLOCK is {}, raise it with `ulimit -l` or LimitMEMLOCK= in a systemd unit", err, limit);
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+
+    pub fn lock_page(page: usize, page_size: usize) -> io::Result<()> {
+        check(unsafe { libc::mlock(page as *const libc::c_void, page_size) })
+    }
+
+    pub fn unlock_page(page: usize, page_size: usize) -> io::Result<()> {
+        check(unsafe { libc::munlock(page as *const libc::c_void, page_size) })
+    }
+
+    pub fn disable_core_dumps() -> io::Result<()> {
+        let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        check(unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) })?;
+        // Also stops other processes of the same user from attaching to or reading this one
+        #[cfg(target_os = "linux")]
+        check(unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) })?;
+        Ok(())
+    }
+
+    pub fn memlock_limit() -> io::Result<Option<u64>> {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        check(unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) })?;
+        if limit.rlim_cur == libc::RLIM_INFINITY {
+            return Ok(None);
+        }
+        // rlim_t is narrower than u64 on some platforms
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(limit.rlim_cur as u64))
+    }
+
+    pub fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::other("not supported on this platform")
+    }
+
+    pub fn lock_page(_page: usize, _page_size: usize) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn unlock_page(_page: usize, _page_size: usize) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn disable_core_dumps() -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn memlock_limit() -> io::Result<Option<u64>> {
+        Err(unsupported())
+    }
+
+    pub fn page_size() -> usize {
+        4096
+    }
+}
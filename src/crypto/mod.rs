@@ -1,3 +1,15 @@
+// A failed KDF, seal or open has a single outcome for callers, and saying which step failed would only
+// help an attacker, so fallible operations here return `Result<_, ()>`
+#![allow(clippy::result_unit_err)]
+
 pub mod hash;
 pub mod aead;
-pub mod rand;
\ No newline at end of file
+pub mod envelope;
+pub mod rand;
+pub mod secret;
+pub mod memory;
+pub mod keyring;
+pub mod index;
+pub mod recovery;
+pub mod shamir;
+pub mod keypair;
\ No newline at end of file
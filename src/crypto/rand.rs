@@ -1,15 +1,83 @@
-use ring::{rand::{self, SecureRandom}};
+use ring::{hmac, rand::{self, SecureRandom}};
+use std::sync::Mutex;
+
+/// A source of random bytes, the operating system's in production
+/// and a seeded deterministic one when output must be reproducible
+pub trait Rng: Send + Sync {
+    /// Fills an array of bytes with random bytes
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - The array of bytes to fill
+    fn fill(&self, out: &mut [u8]) -> Result<(), ()>;
+}
+
+/// Random bytes from the operating system
+pub struct SystemRng(rand::SystemRandom);
+
+impl SystemRng {
+    pub fn new() -> Self {
+        SystemRng(rand::SystemRandom::new())
+    }
+}
+
+impl Default for SystemRng {
+    fn default() -> Self {
+        SystemRng::new()
+    }
+}
+
+impl Rng for SystemRng {
+    fn fill(&self, out: &mut [u8]) -> Result<(), ()> {
+        self.0.fill(out).map_err(|_| ())
+    }
+}
+
+/// Deterministic bytes expanded from a seed, for reproducing sealed output such as golden files.
+/// Block `n` of the output is `HMAC-SHA256(seed, n)` with `n` as a big-endian u64.
+/// Must never be used to seal anything real.
+pub struct SeededRng {
+    key: hmac::Key,
+    /// The next block to output, along with the unused bytes of the last block
+    state: Mutex<(u64, Vec<u8>)>,
+}
+
+impl SeededRng {
+    /// Creates a generator that always produces the same bytes for the same seed
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - The seed to expand
+    pub fn new(seed: &[u8]) -> Self {
+        SeededRng {
+            key: hmac::Key::new(hmac::HMAC_SHA256, seed),
+            state: Mutex::new((0, Vec::new())),
+        }
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill(&self, out: &mut [u8]) -> Result<(), ()> {
+        let mut state = self.state.lock().map_err(|_| ())?;
+        let (counter, buffered) = &mut *state;
+        for byte in out.iter_mut() {
+            if buffered.is_empty() {
+                buffered.extend(hmac::sign(&self.key, &counter.to_be_bytes()).as_ref().iter().rev());
+                *counter += 1;
+            }
+            *byte = buffered.pop().unwrap();
+        }
+        Ok(())
+    }
+}
 
 /// Generate a random vector of bytes of specified length
 /// # Arguments
 ///
+/// * `rng` - The source of random bytes
 /// * `len` - The length of the vector to randomly generate
-pub fn generate_rand_vec(len: usize) -> Result<Vec<u8>, ()> {
+pub fn generate_rand_vec(rng: &dyn Rng, len: usize) -> Result<Vec<u8>, ()> {
     let mut salt = vec!(0u8; len);
-    let rng = rand::SystemRandom::new();
-    let result = rng.fill(&mut salt);
-    if result.is_err() {
-        return Err(())
-    }
+    rng.fill(&mut salt)?;
     Ok(salt)
-}
\ No newline at end of file
+}
@@ -0,0 +1,150 @@
+//! Secrets that unlock a vault besides the password: printable recovery codes and key files.
+//!
+//! A recovery code spells random bytes as words from the BIP39 English word list, 11 bits a word.
+//! The bytes are followed by the first `len * 8 / 32` bits of their SHA-256, so a mistyped word
+//! is caught before any key slot is tried. A key file is random bytes, stored by the User as base64.
+
+use base64ct::{Base64, Encoding};
+use super::{hash::{hash, hkdf_expand, SUBKEY_LEN}, rand::Rng, secret::{SecretBytes, SecretString}};
+
+/// The number of random bytes in a recovery code, spelt as 12 words
+pub const RECOVERY_CODE_LEN: usize = 16;
+/// The number of random bytes in a key file
+pub const KEY_FILE_LEN: usize = 32;
+
+const WORDLIST: &str = include_str!("wordlist/english.txt");
+const BITS_PER_WORD: usize = 11;
+/// The number of leading letters that tell any two words of the list apart
+const UNIQUE_PREFIX_LEN: usize = 4;
+
+const SLOT_KEY_INFO: &[u8] = b"jpassword key slot";
+
+/// Generates a new recovery code, returning its random bytes along with the printable code
+///
+/// # Arguments
+///
+/// * `rng` - The source of the random bytes
+pub fn new_recovery_code(rng: &dyn Rng) -> Result<(SecretBytes, SecretString), ()> {
+    let bytes = SecretBytes::random(rng, RECOVERY_CODE_LEN)?;
+    let code = encode_recovery_code(bytes.as_bytes());
+    Ok((bytes, code))
+}
+
+/// Spells bytes as words followed by their checksum, separated by spaces
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to spell, a multiple of 4 bytes long
+pub fn encode_recovery_code(bytes: &[u8]) -> SecretString {
+    let words: Vec<&str> = WORDLIST.lines().collect();
+    let checksum = hash(bytes);
+    let bit = |i: usize| {
+        let byte = if i < bytes.len() * 8 { bytes[i / 8] } else { checksum[i / 8 - bytes.len()] };
+        (byte >> (7 - i % 8)) & 1
+    };
+
+    let word_count = (bytes.len() * 8 + checksum_bits(bytes.len())) / BITS_PER_WORD;
+    let code: Vec<&str> = (0..word_count)
+        .map(|word| (0..BITS_PER_WORD).fold(0, |index, i| index << 1 | bit(word * BITS_PER_WORD + i) as usize))
+        .map(|index| words[index])
+        .collect();
+    SecretString::from(code.join(" "))
+}
+
+/// Reads the bytes back from a recovery code, returning them if the checksum matches.
+/// Words may be separated by spaces or hyphens, in any case, and may be shortened
+/// to their first four letters
+///
+/// # Arguments
+///
+/// * `code` - The recovery code as typed by the User
+pub fn decode_recovery_code(code: &str) -> Result<SecretBytes, ()> {
+    let words: Vec<&str> = WORDLIST.lines().collect();
+    let code = code.to_lowercase();
+    let indexes = code.split(|c: char| c.is_whitespace() || c == '-')
+        .filter(|word| !word.is_empty())
+        .map(|word| find_word(&words, word))
+        .collect::<Result<Vec<usize>, ()>>()?;
+
+    // Every 33 bits hold 32 bits of the code and one of its checksum
+    let total_bits = indexes.len() * BITS_PER_WORD;
+    if indexes.is_empty() || !total_bits.is_multiple_of(33) {
+        return Err(());
+    }
+    let len = total_bits / 33 * 4;
+
+    let bit = |i: usize| (indexes[i / BITS_PER_WORD] >> (BITS_PER_WORD - 1 - i % BITS_PER_WORD)) & 1;
+    let byte = |n: usize| (0..8).fold(0u8, |byte, i| byte << 1 | bit(n * 8 + i) as u8);
+
+    let mut bytes = SecretBytes::zeroed(len);
+    for (n, out) in bytes.as_mut_bytes().iter_mut().enumerate() {
+        *out = byte(n);
+    }
+
+    let checksum = hash(bytes.as_bytes());
+    let matches = (0..checksum_bits(len))
+        .all(|i| bit(len * 8 + i) == ((checksum[i / 8] >> (7 - i % 8)) & 1) as usize);
+    if !matches {
+        return Err(());
+    }
+    Ok(bytes)
+}
+
+/// Generates a new key file, returning its random bytes along with their base64 encoding
+///
+/// # Arguments
+///
+/// * `rng` - The source of the random bytes
+pub fn new_key_file(rng: &dyn Rng) -> Result<(SecretBytes, SecretString), ()> {
+    let bytes = SecretBytes::random(rng, KEY_FILE_LEN)?;
+    let encoded = SecretString::from(Base64::encode_string(bytes.as_bytes()));
+    Ok((bytes, encoded))
+}
+
+/// Reads the bytes of a key file back from its base64 encoding
+///
+/// # Arguments
+///
+/// * `key_file` - The base64 encoded key file
+pub fn decode_key_file(key_file: &[u8]) -> Result<SecretBytes, ()> {
+    let key_file = std::str::from_utf8(key_file).map_err(|_| ())?;
+    let mut bytes = SecretBytes::zeroed(KEY_FILE_LEN);
+    let len = Base64::decode(key_file.trim(), bytes.as_mut_bytes()).map_err(|_| ())?.len();
+    bytes.as_mut_vec().truncate(len);
+    Ok(bytes)
+}
+
+/// Derives the key a key slot's copy of the vault key is wrapped with from the slot's secret.
+/// The secrets are random, so no slow KDF is needed
+///
+/// # Arguments
+///
+/// * `secret` - The bytes of the recovery code or key file
+pub fn slot_key(secret: &[u8]) -> Result<SecretBytes, ()> {
+    hkdf_expand(secret, SLOT_KEY_INFO, SUBKEY_LEN)
+}
+
+/// The number of checksum bits following `len` bytes
+fn checksum_bits(len: usize) -> usize {
+    len * 8 / 32
+}
+
+/// Finds a word or the only word starting with a prefix of at least four letters
+///
+/// # Arguments
+///
+/// * `words` - The word list
+/// * `word` - The word as typed
+fn find_word(words: &[&str], word: &str) -> Result<usize, ()> {
+    if let Some(index) = words.iter().position(|w| *w == word) {
+        return Ok(index);
+    }
+    if word.len() < UNIQUE_PREFIX_LEN {
+        return Err(());
+    }
+    let mut matches = words.iter().enumerate().filter(|(_, w)| w.starts_with(word));
+    match (matches.next(), matches.next()) {
+        (Some((index, _)), None) => Ok(index),
+        _ => Err(())
+    }
+}
@@ -0,0 +1,148 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use zeroize::Zeroize;
+use std::{fmt, io, mem};
+use super::{memory, rand::Rng};
+
+/// A buffer of secret bytes, such as a key or decrypted plaintext,
+/// that is wiped when dropped and redacted when debug printed.
+/// Its pages are locked into memory when locking is enabled.
+/// It is deliberately not `Clone`, so every copy is explicit.
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    /// Takes ownership of a newly allocated buffer, locking it into memory
+    /// before anything secret is written to it
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The empty or zeroed buffer
+    fn from_buffer(buffer: Vec<u8>) -> Self {
+        memory::lock(buffer.as_ptr(), buffer.capacity());
+        SecretBytes(buffer)
+    }
+
+    /// Creates a buffer of zeroes to be filled with a secret
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - The length of the buffer
+    pub fn zeroed(len: usize) -> Self {
+        SecretBytes::from_buffer(vec![0u8; len])
+    }
+
+    /// Creates an empty buffer that can grow to `capacity` bytes without reallocating
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The number of bytes to reserve
+    pub fn with_capacity(capacity: usize) -> Self {
+        SecretBytes::from_buffer(Vec::with_capacity(capacity))
+    }
+
+    /// Creates a buffer of random bytes, such as a new key
+    ///
+    /// # Arguments
+    ///
+    /// * `rng` - The source of random bytes
+    /// * `len` - The length of the buffer
+    pub fn random(rng: &dyn Rng, len: usize) -> Result<Self, ()> {
+        let mut secret = SecretBytes::zeroed(len);
+        rng.fill(secret.as_mut_bytes())?;
+        Ok(secret)
+    }
+
+    /// The secret bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The secret bytes, to be written to in place
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    /// The underlying buffer, to be sealed or opened in place.
+    /// Must not be grown past its capacity, which would leave a copy behind.
+    pub fn as_mut_vec(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+
+    /// Releases the buffer once it no longer holds a secret, such as after sealing in place
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let buffer = mem::take(&mut self.0);
+        memory::unlock(buffer.as_ptr(), buffer.capacity());
+        buffer
+    }
+}
+
+impl io::Write for SecretBytes {
+    /// Appends bytes to the secret, wiping the old buffer if it has to grow
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.0.len() + bytes.len() > self.0.capacity() {
+            let mut grown = SecretBytes::with_capacity((self.0.len() + bytes.len()).max(self.0.capacity() * 2));
+            grown.0.extend_from_slice(&self.0);
+            *self = grown;
+        }
+        self.0.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        memory::unlock(self.0.as_ptr(), self.0.capacity());
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretBytes([REDACTED])")
+    }
+}
+
+/// A secret string, such as a password, that is wiped when dropped
+/// and redacted when debug printed. Serializes as a plain JSON string.
+#[derive(Default)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// The secret string as bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        SecretString(secret)
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretString)
+    }
+}
@@ -0,0 +1,150 @@
+//! Shamir secret sharing over GF(256), splitting a secret into shares of which any `threshold`
+//! rebuild it while fewer reveal nothing about it.
+//!
+//! Each byte of the secret is the constant term of its own random polynomial of degree
+//! `threshold - 1`, and share `x` holds every polynomial evaluated at `x`. A share is printed
+//! as recovery code words, spelling the threshold, `x`, the id of the split and the evaluations.
+
+use super::{rand::Rng, recovery::{decode_recovery_code, encode_recovery_code}, secret::{SecretBytes, SecretString}};
+
+/// The number of random bytes in a secret split into shares
+pub const SHARED_SECRET_LEN: usize = 16;
+/// The length of a share's threshold, `x` and split id, which precede its evaluations
+const SHARE_HEADER_LEN: usize = 4;
+
+/// Represents one share of a split secret
+pub struct Share {
+    /// The number of shares needed to rebuild the secret
+    pub threshold: u8,
+    /// The point the share's polynomials were evaluated at, never 0
+    pub x: u8,
+    /// A random id shared by every share of one split, so shares of different splits are not mixed
+    pub split_id: u16,
+    /// The polynomials evaluated at `x`, one byte per byte of the secret
+    pub y: SecretBytes,
+}
+
+/// Splits a secret into `count` shares, any `threshold` of which rebuild it
+///
+/// # Arguments
+///
+/// * `rng` - The source of the polynomials' coefficients and the split id
+/// * `secret` - The secret to split
+/// * `threshold` - The number of shares needed to rebuild the secret, at least 1
+/// * `count` - The number of shares to make, at least `threshold`
+pub fn split(rng: &dyn Rng, secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>, ()> {
+    if threshold == 0 || count < threshold {
+        return Err(());
+    }
+
+    let mut split_id = [0u8; 2];
+    rng.fill(&mut split_id)?;
+    let split_id = u16::from_be_bytes(split_id);
+
+    // Coefficients of degree 1 and up for each byte of the secret, the byte itself being the constant
+    let degree = threshold as usize - 1;
+    let coefficients = SecretBytes::random(rng, secret.len() * degree)?;
+
+    Ok((1..=count).map(|x| {
+        let mut y = SecretBytes::zeroed(secret.len());
+        for (i, out) in y.as_mut_bytes().iter_mut().enumerate() {
+            let byte_coefficients = &coefficients.as_bytes()[i * degree..(i + 1) * degree];
+            // Horner's rule from the highest degree down to the constant
+            *out = byte_coefficients.iter().rev()
+                .fold(0, |acc, coefficient| mul(acc, x) ^ coefficient);
+            *out = mul(*out, x) ^ secret[i];
+        }
+        Share { threshold, x, split_id, y }
+    }).collect())
+}
+
+/// Rebuilds a secret from at least `threshold` shares of the same split
+///
+/// # Arguments
+///
+/// * `shares` - The shares, with distinct `x`
+pub fn combine(shares: &[Share]) -> Result<SecretBytes, ()> {
+    let first = shares.first().ok_or(())?;
+    let consistent = shares.iter().all(|share| share.threshold == first.threshold
+        && share.split_id == first.split_id && share.y.as_bytes().len() == first.y.as_bytes().len());
+    if !consistent || shares.len() < first.threshold as usize {
+        return Err(());
+    }
+    for (i, share) in shares.iter().enumerate() {
+        if share.x == 0 || shares[..i].iter().any(|other| other.x == share.x) {
+            return Err(());
+        }
+    }
+
+    // Lagrange interpolation at 0, where subtraction in GF(256) is xor
+    let shares = &shares[..first.threshold as usize];
+    let mut secret = SecretBytes::zeroed(first.y.as_bytes().len());
+    for (j, share) in shares.iter().enumerate() {
+        let basis = shares.iter().enumerate()
+            .filter(|(m, _)| *m != j)
+            .fold(1, |basis, (_, other)| mul(basis, div(other.x, other.x ^ share.x)));
+        for (out, y) in secret.as_mut_bytes().iter_mut().zip(share.y.as_bytes()) {
+            *out ^= mul(basis, *y);
+        }
+    }
+    Ok(secret)
+}
+
+/// Prints a share as words with a checksum
+///
+/// # Arguments
+///
+/// * `share` - The share to print
+pub fn encode_share(share: &Share) -> SecretString {
+    let mut bytes = SecretBytes::with_capacity(SHARE_HEADER_LEN + share.y.as_bytes().len());
+    bytes.as_mut_vec().push(share.threshold);
+    bytes.as_mut_vec().push(share.x);
+    bytes.as_mut_vec().extend_from_slice(&share.split_id.to_be_bytes());
+    bytes.as_mut_vec().extend_from_slice(share.y.as_bytes());
+    encode_recovery_code(bytes.as_bytes())
+}
+
+/// Reads a share back from its words, returning it if the checksum matches
+///
+/// # Arguments
+///
+/// * `share` - The share as typed by its holder
+pub fn decode_share(share: &str) -> Result<Share, ()> {
+    let bytes = decode_recovery_code(share)?;
+    let bytes = bytes.as_bytes();
+    if bytes.len() <= SHARE_HEADER_LEN {
+        return Err(());
+    }
+    let mut y = SecretBytes::zeroed(bytes.len() - SHARE_HEADER_LEN);
+    y.as_mut_bytes().copy_from_slice(&bytes[SHARE_HEADER_LEN..]);
+    Ok(Share {
+        threshold: bytes[0],
+        x: bytes[1],
+        split_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+        y,
+    })
+}
+
+/// Multiplies two elements of GF(256) with the AES polynomial, without branching on either
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = a >> 7;
+        a = (a << 1) ^ (0x1b & carry.wrapping_neg());
+        b >>= 1;
+    }
+    product
+}
+
+/// Divides two elements of GF(256), `b` being nonzero
+fn div(a: u8, b: u8) -> u8 {
+    // The inverse of b is b^254 = b^2 * b^4 * ... * b^128
+    let mut inverse = 1;
+    let mut power = b;
+    for _ in 1..8 {
+        power = mul(power, power);
+        inverse = mul(inverse, power);
+    }
+    mul(a, inverse)
+}
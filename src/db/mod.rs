@@ -1,17 +1,116 @@
 
 use r2d2_sqlite::SqliteConnectionManager;
-use std::path::Path;
-use rusqlite::{params};
+use std::{convert::TryFrom, fs::{File, OpenOptions, TryLockError}, io, path::Path};
+use rusqlite::{Connection, params};
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 
-/// Creates a SQLite database if it does not exist 
+/// The fewest connections a Pool holds, left for work done outside the worker threads
+const MIN_POOL_SIZE: u32 = 10;
+
+/// Creates a SQLite database if it does not exist
 /// and returns a Pool for connections to this database
-pub fn create_db_then_pool(file: &Path) -> Pool {
+///
+/// # Arguments
+///
+/// * `file` - The path of the database
+/// * `worker_threads` - The number of worker threads, each of which may hold a connection at once
+pub fn create_db_then_pool(file: &Path, worker_threads: usize) -> Pool {
     let manager = SqliteConnectionManager::file(file);
-    let pool = Pool::new(manager).unwrap();
-    
-    let conn = pool.get().unwrap();
-    conn.execute("CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY, hash TEXT, password BLOB, salt BLOB, data BLOB)", params![]).unwrap();
+    let max_size = u32::try_from(worker_threads).unwrap_or(u32::MAX).max(MIN_POOL_SIZE);
+    let pool = Pool::builder().max_size(max_size).build(manager).unwrap();
+
+    let mut conn = pool.get().unwrap();
+    conn.execute("CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY AUTOINCREMENT, hash TEXT, password BLOB, salt BLOB, data BLOB)", params![]).unwrap();
+    add_column_if_missing(&conn, "users", "vault_key", "BLOB");
+    add_column_if_missing(&conn, "users", "revision", "INTEGER");
+    add_column_if_missing(&conn, "users", "index_key", "INTEGER");
+    add_column_if_missing(&conn, "users", "updated_at", "INTEGER");
+    add_column_if_missing(&conn, "users", "public_key", "BLOB");
+    add_column_if_missing(&conn, "users", "private_key", "BLOB");
+    add_column_if_missing(&conn, "users", "decoy_password", "BLOB");
+    add_column_if_missing(&conn, "users", "decoy_key", "BLOB");
+    add_column_if_missing(&conn, "users", "decoy_data", "BLOB");
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS users_hash ON users (hash)", params![]).unwrap();
+    conn.execute("CREATE TABLE IF NOT EXISTS key_slots (id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL, \
+        kind TEXT NOT NULL, wrapped_key BLOB NOT NULL, created_at INTEGER)", params![]).unwrap();
+    conn.execute("CREATE INDEX IF NOT EXISTS key_slots_user_id ON key_slots (user_id)", params![]).unwrap();
+    conn.execute("CREATE TABLE IF NOT EXISTS devices (id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL, \
+        name TEXT NOT NULL, password BLOB NOT NULL, wrapped_key BLOB NOT NULL, attempts INTEGER NOT NULL, \
+        created_at INTEGER, last_used_at INTEGER)", params![]).unwrap();
+    conn.execute("CREATE INDEX IF NOT EXISTS devices_user_id ON devices (user_id)", params![]).unwrap();
+    conn.execute("CREATE TABLE IF NOT EXISTS escrow (user_id INTEGER PRIMARY KEY, public_key BLOB NOT NULL, \
+        wrapped_key BLOB NOT NULL, created_at INTEGER)", params![]).unwrap();
+    conn.execute("CREATE TABLE IF NOT EXISTS audit_log (id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL, \
+        action TEXT NOT NULL, actor TEXT, detail TEXT, created_at INTEGER NOT NULL)", params![]).unwrap();
+    conn.execute("CREATE INDEX IF NOT EXISTS audit_log_user_id ON audit_log (user_id)", params![]).unwrap();
+    conn.execute("CREATE TABLE IF NOT EXISTS emergency_grants (id INTEGER PRIMARY KEY, owner_id INTEGER NOT NULL, \
+        grantee_id INTEGER NOT NULL, access TEXT NOT NULL, wait_seconds INTEGER NOT NULL, wrapped_key BLOB NOT NULL, \
+        status TEXT NOT NULL, requested_at INTEGER, created_at INTEGER)", params![]).unwrap();
+    never_reuse_user_ids(&mut conn);
 
     pool
-}
\ No newline at end of file
+}
+
+/// Takes a lock on the lock file beside a database, returning the file, which holds the lock until dropped.
+/// Servers share the lock, while commands that change keys a running server holds in memory take it alone.
+/// Fails with `io::ErrorKind::WouldBlock` if the lock is held otherwise
+///
+/// # Arguments
+///
+/// * `file` - The path of the database
+/// * `exclusive` - Whether to take the lock alone
+pub fn lock(file: &Path, exclusive: bool) -> io::Result<File> {
+    let lock_file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+        .open(file.with_extension("lock"))?;
+    let locked = if exclusive { lock_file.try_lock() } else { lock_file.try_lock_shared() };
+    match locked {
+        Ok(()) => Ok(lock_file),
+        Err(TryLockError::WouldBlock) => Err(io::Error::new(io::ErrorKind::WouldBlock,
+            format!("{} is locked", file.with_extension("lock").display()))),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
+
+/// Rebuilds a users table created by an older version without AUTOINCREMENT, under which SQLite
+/// hands the id of a deleted User to the next new one and that User would inherit the audit trail
+/// left behind. Ids already used by a deleted User's audit trail are skipped too
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+fn never_reuse_user_ids(conn: &mut Connection) {
+    let sql: String = conn.query_row("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'users'",
+        params![], |row| row.get(0)).unwrap();
+    if sql.contains("AUTOINCREMENT") {
+        return;
+    }
+    let columns = "id, hash, password, salt, data, vault_key, revision, index_key, updated_at, public_key, \
+        private_key, decoy_password, decoy_key, decoy_data";
+    let tx = conn.transaction().unwrap();
+    tx.execute("ALTER TABLE users RENAME TO users_rowid", params![]).unwrap();
+    tx.execute("CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, hash TEXT, password BLOB, salt BLOB, \
+        data BLOB, vault_key BLOB, revision INTEGER, index_key INTEGER, updated_at INTEGER, public_key BLOB, \
+        private_key BLOB, decoy_password BLOB, decoy_key BLOB, decoy_data BLOB)", params![]).unwrap();
+    tx.execute(&format!("INSERT INTO users ({}) SELECT {} FROM users_rowid", columns, columns), params![]).unwrap();
+    tx.execute("DROP TABLE users_rowid", params![]).unwrap();
+    tx.execute("CREATE UNIQUE INDEX users_hash ON users (hash)", params![]).unwrap();
+    tx.execute("DELETE FROM sqlite_sequence WHERE name = 'users'", params![]).unwrap();
+    tx.execute("INSERT INTO sqlite_sequence (name, seq) SELECT 'users', MAX(COALESCE((SELECT MAX(id) FROM users), 0), \
+        COALESCE((SELECT MAX(user_id) FROM audit_log), 0))", params![]).unwrap();
+    tx.commit().unwrap();
+}
+
+/// Adds a column to an existing table if a database created by an older version lacks it
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `table` - The name of the table
+/// * `column` - The name of the column to add
+/// * `definition` - The type and constraints of the column
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) {
+    let mut stmt = conn.prepare(&format!("SELECT * FROM pragma_table_info('{}') WHERE name = ?", table)).unwrap();
+    if !stmt.exists(params![column]).unwrap() {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), params![]).unwrap();
+    }
+}
@@ -0,0 +1,11 @@
+//! A RESTful password manager. The server is in `main.rs`, and everything it is built from
+//! is exposed here so it can be exercised outside of the server, such as by tests and fuzzers.
+
+pub mod db;
+pub mod config;
+pub mod crypto;
+pub mod api;
+pub mod models;
+pub mod worker;
+pub mod cli;
+pub mod clock;
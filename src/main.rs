@@ -1,12 +1,12 @@
-mod db;
-mod config;
-mod crypto;
-mod api;
-mod models;
-
 use actix_web::{http, App, HttpServer};
 use actix_cors::{Cors};
-use db::create_db_then_pool;
+use jpassword::{cli, config, db::{self, create_db_then_pool, Pool}, config::settings::{MemorySettings, Settings}, worker::WorkerPool,
+    crypto::memory, models::user::UserError,
+    models::emergency};
+use std::{io, path::Path, time::Duration};
+
+/// How often requests for emergency access are checked for a waiting period that has run out
+const EMERGENCY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 /// Creates an HTTP server serving as a RESTful interface for
 /// password management
@@ -15,8 +15,44 @@ async fn main() -> std::io::Result<()> {
 
     let mut path_to_db = std::env::current_exe().unwrap();
     path_to_db.pop();
-    path_to_db.push("jpassword.db".to_string());
-    let pool = create_db_then_pool(path_to_db.as_path());
+    path_to_db.push("jpassword.db");
+    // Secret buffers are only locked if they are allocated once locking is enabled, so the
+    // memory is protected before the key files are loaded
+    protect_memory(&MemorySettings::from_env());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("init-kek") {
+        return cli::init_kek();
+    }
+    let mut settings = Settings::from_env();
+    let pool = create_db_then_pool(path_to_db.as_path(), settings.worker_threads);
+
+    if let Some(kek_file) = &settings.kek_file {
+        if kek_file.canonicalize().ok().as_deref().and_then(Path::parent) == path_to_db.parent() {
+            eprintln!("Warning: {} is beside the database, keep it elsewhere so a copy of the database \
+                alone cannot be attacked", kek_file.display());
+        }
+    }
+
+    if !args.is_empty() {
+        return cli::run(&args, &settings, &pool, &path_to_db);
+    }
+
+    // Held for as long as the server runs, so rotate-kek cannot change the keys it holds in memory
+    let _lock = db::lock(&path_to_db, false).map_err(|err| match err.kind() {
+        io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::WouldBlock,
+            "a command is rotating the key-encryption key, start the server once it is done"),
+        _ => err,
+    })?;
+
+    if let Some(target) = settings.kdf_target {
+        match settings.calibrate_kdf(target) {
+            Ok(elapsed) => println!("Calibrated the KDF to {:?}, taking {:?}", settings.kdf, elapsed),
+            Err(()) => eprintln!("Warning: could not calibrate the KDF, keeping {:?}", settings.kdf),
+        }
+    }
+
+    let workers = WorkerPool::new(settings.worker_threads, settings.worker_queue_len);
+    actix_rt::spawn(approve_expired_grants(pool.clone(), settings.clone(), workers.clone()));
 
     HttpServer::new(move || {
         App::new()
@@ -25,13 +61,69 @@ async fn main() -> std::io::Result<()> {
                 .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
                 .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
                 .allowed_header(http::header::CONTENT_TYPE)
+                .expose_headers(vec!["deprecation"])
                 .max_age(3600)
                 .finish())
             .data(pool.clone())
+            .data(settings.clone())
+            .data(workers.clone())
             .configure(config::app::configure)
             
     })
     .bind("127.0.0.1:8088")?
     .run()
     .await
-}
\ No newline at end of file
+}
+
+/// Approves requests for emergency access whose owner did not reject them within the waiting period,
+/// checking every `EMERGENCY_CHECK_INTERVAL` for as long as the server runs
+///
+/// # Arguments
+///
+/// * `pool` - The pool of connections to the database
+/// * `settings` - The server configuration
+/// * `workers` - The pool the database work is run on
+async fn approve_expired_grants(pool: Pool, settings: Settings, workers: WorkerPool) {
+    let mut interval = actix_rt::time::interval(EMERGENCY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pool = pool.clone();
+        let now = settings.clock.unix_seconds();
+        let approved = workers.run(move || {
+            let conn = pool.get().map_err(|_| UserError::Internal)?;
+            emergency::approve_expired(&conn, now)
+        }).await;
+        match approved {
+            Ok(Ok(0)) => (),
+            Ok(Ok(count)) => println!("Approved {} requests for emergency access", count),
+            Ok(Err(_)) => eprintln!("Warning: could not approve expired requests for emergency access"),
+            Err(err) => eprintln!("Warning: could not check requests for emergency access ({:?})", err),
+        }
+    }
+}
+
+/// Keeps secrets held by the server out of core dumps and swap as configured,
+/// reporting when the process is not allowed to
+///
+/// # Arguments
+///
+/// * `settings` - How the memory of the process is protected
+fn protect_memory(settings: &MemorySettings) {
+    if settings.disable_core_dumps {
+        if let Err(err) = memory::disable_core_dumps() {
+            eprintln!("Warning: could not disable core dumps ({})", err);
+        }
+    }
+
+    if settings.lock_memory {
+        memory::enable_locking();
+        let needed = settings.worker_threads as u64 * memory::LOCKED_BYTES_PER_WORKER;
+        match memory::memlock_limit() {
+            Ok(Some(limit)) if limit < needed =>
+                eprintln!("Warning: RLIMIT_MEMLOCK is {} bytes but {} worker threads may lock up to {} bytes, \
+                    raise it with `ulimit -l` or LimitMEMLOCK= in a systemd unit", limit, settings.worker_threads, needed),
+            Ok(_) => (),
+            Err(err) => eprintln!("Warning: could not read RLIMIT_MEMLOCK ({})", err),
+        }
+    }
+}
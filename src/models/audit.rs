@@ -0,0 +1,105 @@
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use super::user::UserError;
+
+/// Represents the kinds of event recorded in the audit trail
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    /// A User's vault key was wrapped to an escrow public key
+    EscrowWrapped,
+    /// A recovery officer opened a User's vault with the escrow private key and set a new password
+    EscrowRecovered,
+}
+
+impl AuditAction {
+    /// The name the action is stored under in the `action` column
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditAction::EscrowWrapped => "escrow_wrapped",
+            AuditAction::EscrowRecovered => "escrow_recovered",
+        }
+    }
+
+    /// Returns the action stored under a name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The `action` column of an event
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "escrow_wrapped" => Some(AuditAction::EscrowWrapped),
+            "escrow_recovered" => Some(AuditAction::EscrowRecovered),
+            _ => None
+        }
+    }
+}
+
+/// Represents an event recorded in the audit trail of a User. Events are removed along with the User
+#[derive(Serialize)]
+pub struct AuditEvent {
+    /// The id of the event
+    pub id: i64,
+    /// What happened
+    pub action: AuditAction,
+    /// Who made it happen, if not the server
+    pub actor: Option<String>,
+    /// The fingerprint of the key involved, if any
+    pub detail: Option<String>,
+    /// When it happened as seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+/// Lists the events recorded for a User, oldest first
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn list(conn: &Connection, user_id: i64) -> Result<Vec<AuditEvent>, UserError> {
+    let mut stmt = conn.prepare("SELECT id, action, actor, detail, created_at FROM audit_log WHERE user_id = ? ORDER BY id")
+        .map_err(|_| UserError::Internal)?;
+    let mut rows = stmt.query(params![user_id]).map_err(|_| UserError::Internal)?;
+    let mut events = Vec::new();
+    while let Some(row) = rows.next().map_err(|_| UserError::Internal)? {
+        let action: String = row.get(1).map_err(|_| UserError::Corrupt)?;
+        events.push(AuditEvent {
+            id: row.get(0).map_err(|_| UserError::Corrupt)?,
+            action: AuditAction::from_name(&action).ok_or(UserError::Corrupt)?,
+            actor: row.get(2).map_err(|_| UserError::Corrupt)?,
+            detail: row.get(3).map_err(|_| UserError::Corrupt)?,
+            created_at: row.get(4).map_err(|_| UserError::Corrupt)?,
+        });
+    }
+    Ok(events)
+}
+
+/// Records an event for a User
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `action` - What happened
+/// * `actor` - Who made it happen, if not the server
+/// * `detail` - The fingerprint of the key involved, if any
+/// * `created_at` - The current time as seconds since the Unix epoch
+pub fn record(conn: &Connection, user_id: i64, action: AuditAction, actor: Option<&str>, detail: Option<&str>,
+    created_at: i64) -> Result<(), UserError> {
+    conn.execute("INSERT INTO audit_log (user_id, action, actor, detail, created_at) VALUES (?, ?, ?, ?, ?)",
+        params![user_id, action.name(), actor, detail, created_at])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
+
+/// Removes every event recorded for a User
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn delete_all(conn: &Connection, user_id: i64) -> Result<(), UserError> {
+    conn.execute("DELETE FROM audit_log WHERE user_id = ?", params![user_id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
@@ -1,25 +1,137 @@
 use serde::{Serialize, Deserialize};
+use serde_json::error::Category;
+use std::convert::TryFrom;
+use crate::crypto::{rand::Rng, secret::SecretString};
+use super::item::{ApiKey, Card, Identity, Item, ItemType, Login, Note, Wifi, WifiSecurity};
 
 /// Represents a User's credential for another application
 #[derive(Serialize, Deserialize)]
+#[serde(try_from = "CredentialFields")]
 pub struct Credential {
+    /// The random UUID of the Credential, empty in vaults saved before Credentials had ids
+    /// until they are assigned
+    pub id: String,
     /// The name of the Credential
     pub name: String,
-    /// The url of the Credential
-    pub url: String,
-    /// The username associated with the Credential
-    pub username: String,
-    /// The password associated with the Credential
-    pub password: String
+    /// What the Credential holds, a login if saved before Credentials had types
+    #[serde(flatten)]
+    pub item: Item,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Represents the fields a Credential of any type may have, read from JSON before they are checked
+/// against the Credential's type. Each field is read straight into its place rather than through
+/// a buffer of the whole object, which would leave copies of the secrets behind
+#[derive(Deserialize)]
+struct CredentialFields {
+    #[serde(default)]
+    id: String,
+    name: String,
+    /// The type of the item, absent in vaults saved before items had types
+    #[serde(rename = "type")]
+    item_type: Option<ItemType>,
+    url: Option<String>,
+    username: Option<SecretString>,
+    password: Option<SecretString>,
+    text: Option<SecretString>,
+    cardholder: Option<String>,
+    number: Option<SecretString>,
+    expiry_month: Option<u8>,
+    expiry_year: Option<u16>,
+    cvv: Option<SecretString>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    email: Option<String>,
+    phone: Option<String>,
+    address: Option<String>,
+    document_number: Option<SecretString>,
+    key: Option<SecretString>,
+    secret: Option<SecretString>,
+    ssid: Option<String>,
+    security: Option<WifiSecurity>,
+}
+
+/// Returns a field the item's type requires, or the error naming it if absent
+///
+/// # Arguments
+///
+/// * `field` - The field as read from JSON
+/// * `name` - The name of the field
+fn required<T>(field: Option<T>, name: &str) -> Result<T, String> {
+    field.ok_or_else(|| format!("missing field `{}`", name))
+}
+
+impl TryFrom<CredentialFields> for Credential {
+    type Error = String;
+
+    /// Builds the item of the Credential's type from its fields, reading one without a type
+    /// as a login as saved before items had types
+    fn try_from(fields: CredentialFields) -> Result<Self, Self::Error> {
+        let item = match fields.item_type.unwrap_or(ItemType::Login) {
+            ItemType::Login => Item::Login(Login {
+                url: required(fields.url, "url")?,
+                username: required(fields.username, "username")?,
+                password: required(fields.password, "password")?,
+            }),
+            ItemType::Note => Item::Note(Note {
+                text: required(fields.text, "text")?,
+            }),
+            ItemType::Card => Item::Card(Card {
+                cardholder: fields.cardholder.unwrap_or_default(),
+                number: required(fields.number, "number")?,
+                expiry_month: required(fields.expiry_month, "expiry_month")?,
+                expiry_year: required(fields.expiry_year, "expiry_year")?,
+                cvv: fields.cvv.unwrap_or_default(),
+            }),
+            ItemType::Identity => Item::Identity(Identity {
+                first_name: fields.first_name.unwrap_or_default(),
+                last_name: fields.last_name.unwrap_or_default(),
+                email: fields.email.unwrap_or_default(),
+                phone: fields.phone.unwrap_or_default(),
+                address: fields.address.unwrap_or_default(),
+                document_number: fields.document_number.unwrap_or_default(),
+            }),
+            ItemType::ApiKey => Item::ApiKey(ApiKey {
+                key: required(fields.key, "key")?,
+                secret: fields.secret.unwrap_or_default(),
+                url: fields.url.unwrap_or_default(),
+            }),
+            ItemType::Wifi => Item::Wifi(Wifi {
+                ssid: required(fields.ssid, "ssid")?,
+                security: required(fields.security, "security")?,
+                password: fields.password.unwrap_or_default(),
+            }),
+        };
+        Ok(Credential { id: fields.id, name: fields.name, item })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
 /// Represents a list of the User's saved credentials
 pub struct Credentials {
     /// A vector of the User's credentials
     pub credentials: Vec<Credential>
 }
 
+/// Represents the reasons a Credential cannot be added or updated
+#[derive(Debug, PartialEq, Eq)]
+pub enum CredentialError {
+    /// No Credential has the id
+    NotFound,
+    /// The fields of the item do not suit its type
+    InvalidItem,
+    /// No id could be generated
+    Internal,
+}
+
+/// Represents the reasons a decrypted vault cannot be decoded into Credentials
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The vault is not UTF-8 JSON or ends early
+    Malformed,
+    /// The vault is JSON but does not hold a list of Credentials
+    Unexpected,
+}
+
 impl Credentials {
     /// Creates an empty list of Credentials, returning it
     pub fn new() -> Self {
@@ -28,39 +140,71 @@ impl Credentials {
         }
     }
 
-    /// Adds a new credential in the list of Credentials
+    /// Decodes the decrypted JSON of a vault into a list of Credentials, returning it.
+    /// Never panics, whatever the vault holds
     ///
     /// # Arguments
     ///
-    /// * `name` - The name of the Credential
-    /// * `url` - The url of the Credential
-    /// * `username` - The username associated with the Credential
-    /// * `password` - The password associated with the Credential
-    pub fn create(&mut self, name: String, url: String, username: String, password: String) {
-        self.credentials.push(Credential {
-            name: name,
-            url: url,
-            username: username,
-            password: password
+    /// * `json` - The decrypted vault
+    pub fn decode(json: &[u8]) -> Result<Self, DecodeError> {
+        serde_json::from_slice(json).map_err(|err| match err.classify() {
+            Category::Data => DecodeError::Unexpected,
+            Category::Io | Category::Syntax | Category::Eof => DecodeError::Malformed,
         })
     }
 
-    /// Updates a Credential in the list of Credentials
+    /// Gives every Credential without an id a new one, returning whether any was given
     ///
     /// # Arguments
     ///
-    /// * `i` - The index of the Credential to update
-    /// * `new_cred` - The updated credential to replace the existing with
-    pub fn update(&mut self, i: usize, new_cred: Credential) -> Result<(), ()> {
-        if i >= self.credentials.len() {
-            return Err(());
+    /// * `rng` - The source of the ids
+    pub fn assign_ids(&mut self, rng: &dyn Rng) -> Result<bool, CredentialError> {
+        let mut assigned = false;
+        for credential in self.credentials.iter_mut().filter(|credential| credential.id.is_empty()) {
+            credential.id = new_id(rng).map_err(|_| CredentialError::Internal)?;
+            assigned = true;
         }
+        Ok(assigned)
+    }
 
-        let mut credential = self.credentials.get_mut(i).unwrap();
+    /// Adds a new credential in the list of Credentials under a new id
+    ///
+    /// # Arguments
+    ///
+    /// * `rng` - The source of the id
+    /// * `name` - The name of the Credential
+    /// * `item` - What the Credential holds
+    pub fn create(&mut self, rng: &dyn Rng, name: String, item: Item) -> Result<(), CredentialError> {
+        item.validate()?;
+        self.credentials.push(Credential {
+            id: new_id(rng).map_err(|_| CredentialError::Internal)?,
+            name,
+            item
+        });
+        Ok(())
+    }
+
+    /// Returns the id of the Credential at a position in the list of Credentials, `None` if there is none
+    ///
+    /// # Arguments
+    ///
+    /// * `i` - The index of the Credential
+    pub fn id_at(&self, i: usize) -> Option<&str> {
+        self.credentials.get(i).map(|credential| credential.id.as_str())
+    }
+
+    /// Updates a Credential in the list of Credentials, keeping its id
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of the Credential to update
+    /// * `new_cred` - The updated credential to replace the existing with
+    pub fn update(&mut self, id: &str, new_cred: Credential) -> Result<(), CredentialError> {
+        new_cred.item.validate()?;
+        let credential = self.credentials.iter_mut().find(|credential| credential.id == id)
+            .ok_or(CredentialError::NotFound)?;
         credential.name = new_cred.name;
-        credential.url = new_cred.url;
-        credential.username = new_cred.username;
-        credential.password = new_cred.password;
+        credential.item = new_cred.item;
 
         Ok(())
     }
@@ -69,13 +213,25 @@ impl Credentials {
     ///
     /// # Arguments
     ///
-    /// * `i` - The indexof the Credential to delete
-    pub fn delete(&mut self, i: usize) -> Result<(), ()> {
-        if i >= self.credentials.len() {
-            return Err(());
-        }
+    /// * `id` - The id of the Credential to delete
+    pub fn delete(&mut self, id: &str) -> Result<(), CredentialError> {
+        let i = self.credentials.iter().position(|credential| credential.id == id).ok_or(CredentialError::NotFound)?;
         self.credentials.remove(i);
         Ok(())
     }
 
+}
+
+/// Generates a random version 4 UUID, hyphenated in lowercase
+///
+/// # Arguments
+///
+/// * `rng` - The source of the UUID
+fn new_id(rng: &dyn Rng) -> Result<String, ()> {
+    let mut bytes = [0u8; 16];
+    rng.fill(&mut bytes)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    Ok(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
 }
\ No newline at end of file
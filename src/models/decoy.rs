@@ -0,0 +1,114 @@
+use rusqlite::{Connection, params};
+use serde::{Serialize, Deserialize};
+use crate::crypto::secret::SecretBytes;
+use super::{credentials::Credentials, user::UserError};
+
+/// The size sealed decoys are padded to a multiple of, so an empty filler and a decoy
+/// holding a few credentials seal to the same length
+const DECOY_BLOCK_LEN: usize = 4096;
+
+/// Represents what happens to the real vault when the duress password is used
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DuressAction {
+    /// The real vault is left as it is
+    None,
+    /// The password stops opening the real vault until it is reset with a recovery code, key file or shares
+    Lock,
+    /// The real vault is locked and every key slot, emergency access grant it owns, escrowed key and its
+    /// emergency access keypair are removed, so its credentials can never be opened again
+    Wipe,
+}
+
+/// The stored blobs of a User's decoy, each possibly wrapped with a key-encryption key
+pub struct DecoyColumns {
+    /// The PHC string of the duress password, or of no known password
+    pub password: Vec<u8>,
+    /// The decoy's vault key wrapped by the encryption subkey of the duress password's master key
+    pub vault_key: Vec<u8>,
+    /// The decoy's credentials and duress action sealed with the decoy's vault key
+    pub data: Vec<u8>,
+}
+
+/// The decrypted contents of a decoy, as sealed
+#[derive(Serialize)]
+struct SealedDecoy<'a> {
+    action: DuressAction,
+    credentials: &'a Credentials,
+}
+
+/// The decrypted contents of a decoy, as opened
+#[derive(Deserialize)]
+struct OpenedDecoy {
+    action: DuressAction,
+    credentials: Credentials,
+}
+
+/// Encodes a decoy as JSON padded with trailing spaces to a multiple of `DECOY_BLOCK_LEN`
+///
+/// # Arguments
+///
+/// * `action` - What happens to the real vault when the duress password is used
+/// * `credentials` - The decoy's credentials
+pub fn encode(action: DuressAction, credentials: &Credentials) -> Result<SecretBytes, UserError> {
+    let mut json = SecretBytes::with_capacity(DECOY_BLOCK_LEN);
+    serde_json::to_writer(&mut json, &SealedDecoy { action, credentials }).map_err(|_| UserError::Internal)?;
+    let padded_len = (json.as_bytes().len() / DECOY_BLOCK_LEN + 1) * DECOY_BLOCK_LEN;
+    json.as_mut_vec().resize(padded_len, b' ');
+    Ok(json)
+}
+
+/// Decodes a decoy encoded by `encode`, returning its duress action and credentials
+///
+/// # Arguments
+///
+/// * `json` - The decrypted decoy
+pub fn decode(json: &[u8]) -> Result<(DuressAction, Credentials), UserError> {
+    let decoy: OpenedDecoy = serde_json::from_slice(json).map_err(|_| UserError::Corrupt)?;
+    Ok((decoy.action, decoy.credentials))
+}
+
+/// Fetches the blobs of a User's decoy, `None` if the row has none yet
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn columns(conn: &Connection, user_id: i64) -> Result<Option<DecoyColumns>, UserError> {
+    let row: [Option<Vec<u8>>; 3] = conn.query_row("SELECT decoy_password, decoy_key, decoy_data FROM users WHERE id = ?",
+        params![user_id], |row| Ok([row.get(0)?, row.get(1)?, row.get(2)?]))
+        .map_err(|_| UserError::Corrupt)?;
+    match row {
+        [Some(password), Some(vault_key), Some(data)] => Ok(Some(DecoyColumns { password, vault_key, data })),
+        [None, None, None] => Ok(None),
+        _ => Err(UserError::Corrupt)
+    }
+}
+
+/// Stores every blob of a User's decoy
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `decoy` - The blobs to store
+pub fn store(conn: &Connection, user_id: i64, decoy: &DecoyColumns) -> Result<(), UserError> {
+    conn.execute("UPDATE users SET decoy_password = ?, decoy_key = ?, decoy_data = ? WHERE id = ?",
+        params![decoy.password, decoy.vault_key, decoy.data, user_id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
+
+/// Stores the sealed credentials of a User's decoy, keeping its password and vault key
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `data` - The sealed decoy
+/// * `updated_at` - The current time as seconds since the Unix epoch
+pub fn store_data(conn: &Connection, user_id: i64, data: &[u8], updated_at: i64) -> Result<(), UserError> {
+    conn.execute("UPDATE users SET decoy_data = ?, updated_at = ? WHERE id = ?", params![data, updated_at, user_id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
@@ -0,0 +1,154 @@
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use super::user::UserError;
+
+/// The most devices a User may enroll at once
+pub const MAX_DEVICES: usize = 16;
+/// The number of wrong PINs after which a device's wrapped key is destroyed
+pub const MAX_PIN_ATTEMPTS: i64 = 5;
+/// The fewest characters a PIN may have
+pub const MIN_PIN_LEN: usize = 4;
+
+/// Represents a device enrolled to unlock a User's vault with a PIN. Listed to the User
+/// without the PIN hash or the wrapped key
+#[derive(Serialize)]
+pub struct Device {
+    /// The id of the device
+    pub id: i64,
+    /// The name the User gave the device
+    pub name: String,
+    /// The number of wrong PINs since the device last unlocked the vault
+    pub attempts: i64,
+    /// When the device was enrolled as seconds since the Unix epoch
+    pub created_at: Option<i64>,
+    /// When the device last unlocked the vault as seconds since the Unix epoch
+    pub last_used_at: Option<i64>,
+}
+
+/// Lists the devices of a User
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn list(conn: &Connection, user_id: i64) -> Result<Vec<Device>, UserError> {
+    let mut stmt = conn.prepare("SELECT id, name, attempts, created_at, last_used_at FROM devices WHERE user_id = ? ORDER BY id")
+        .map_err(|_| UserError::Internal)?;
+    let mut rows = stmt.query(params![user_id]).map_err(|_| UserError::Internal)?;
+    let mut devices = Vec::new();
+    while let Some(row) = rows.next().map_err(|_| UserError::Internal)? {
+        devices.push(Device {
+            id: row.get(0).map_err(|_| UserError::Corrupt)?,
+            name: row.get(1).map_err(|_| UserError::Corrupt)?,
+            attempts: row.get(2).map_err(|_| UserError::Corrupt)?,
+            created_at: row.get(3).map_err(|_| UserError::Corrupt)?,
+            last_used_at: row.get(4).map_err(|_| UserError::Corrupt)?,
+        });
+    }
+    Ok(devices)
+}
+
+/// Counts the devices of a User
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn count(conn: &Connection, user_id: i64) -> Result<usize, UserError> {
+    conn.query_row("SELECT COUNT(*) FROM devices WHERE user_id = ?", params![user_id], |row| row.get::<_, i64>(0))
+        .map(|count| count as usize)
+        .map_err(|_| UserError::Internal)
+}
+
+/// Fetches the PIN hash and wrapped vault key of one of a User's devices
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `id` - The id of the device
+pub fn keys(conn: &Connection, user_id: i64, id: i64) -> Result<(Vec<u8>, Vec<u8>), UserError> {
+    let mut stmt = conn.prepare("SELECT password, wrapped_key FROM devices WHERE user_id = ? AND id = ?")
+        .map_err(|_| UserError::Internal)?;
+    let mut rows = stmt.query(params![user_id, id]).map_err(|_| UserError::Internal)?;
+    let row = rows.next().map_err(|_| UserError::Internal)?.ok_or(UserError::InvalidCredentials)?;
+    Ok((row.get(0).map_err(|_| UserError::Corrupt)?, row.get(1).map_err(|_| UserError::Corrupt)?))
+}
+
+/// Stores a new device, returning its id
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `name` - The name the User gave the device
+/// * `password` - The PHC string of the device secret and PIN
+/// * `wrapped_key` - The vault key wrapped with the encryption subkey of the device secret and PIN
+/// * `created_at` - The current time as seconds since the Unix epoch
+pub fn insert(conn: &Connection, user_id: i64, name: &str, password: &[u8], wrapped_key: &[u8], created_at: i64) -> Result<i64, UserError> {
+    let mut stmt = conn.prepare("INSERT INTO devices (user_id, name, password, wrapped_key, attempts, created_at) \
+        VALUES (?, ?, ?, ?, 0, ?)")
+        .map_err(|_| UserError::Internal)?;
+    stmt.insert(params![user_id, name, password, wrapped_key, created_at]).map_err(|_| UserError::Internal)
+}
+
+/// Counts an attempt at a device's PIN before the PIN is checked, so attempts made at once
+/// cannot exceed the limit. Returns the attempts made since the device last unlocked the vault,
+/// failing with `InvalidCredentials` if the device does not exist or has no attempts left
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `id` - The id of the device
+pub fn reserve_attempt(conn: &Connection, user_id: i64, id: i64) -> Result<i64, UserError> {
+    let reserved = conn.execute("UPDATE devices SET attempts = attempts + 1 WHERE user_id = ? AND id = ? AND attempts < ?",
+        params![user_id, id, MAX_PIN_ATTEMPTS]);
+    match reserved {
+        Ok(1) => (),
+        Ok(_) => return Err(UserError::InvalidCredentials),
+        Err(_) => return Err(UserError::Internal)
+    }
+    conn.query_row("SELECT attempts FROM devices WHERE id = ?", params![id], |row| row.get(0))
+        .map_err(|_| UserError::Internal)
+}
+
+/// Clears the attempts of a device once it unlocked the vault
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `id` - The id of the device
+/// * `used_at` - The current time as seconds since the Unix epoch
+pub fn reset_attempts(conn: &Connection, id: i64, used_at: i64) -> Result<(), UserError> {
+    conn.execute("UPDATE devices SET attempts = 0, last_used_at = ? WHERE id = ?", params![used_at, id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
+
+/// Removes one of a User's devices
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `id` - The id of the device
+pub fn delete(conn: &Connection, user_id: i64, id: i64) -> Result<(), UserError> {
+    match conn.execute("DELETE FROM devices WHERE user_id = ? AND id = ?", params![user_id, id]) {
+        Ok(1) => Ok(()),
+        Ok(_) => Err(UserError::NotFound),
+        Err(_) => Err(UserError::Internal)
+    }
+}
+
+/// Removes every device of a User
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn delete_all(conn: &Connection, user_id: i64) -> Result<(), UserError> {
+    conn.execute("DELETE FROM devices WHERE user_id = ?", params![user_id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
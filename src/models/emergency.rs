@@ -0,0 +1,275 @@
+use rusqlite::{Connection, Row, params};
+use serde::{Serialize, Deserialize};
+use super::user::UserError;
+
+/// The number of seconds in a day, which waiting periods are given in
+pub const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Represents what a grantee may do with a vault once a grant is approved
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// The grantee may read the credentials
+    Read,
+    /// The grantee may set a new password for the owner
+    Takeover,
+}
+
+/// Represents where a grant stands
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The grantee has not requested access
+    Idle,
+    /// The grantee has requested access and the waiting period is running
+    Requested,
+    /// The owner approved the request or did not reject it in time
+    Approved,
+    /// The owner rejected the request, which the grantee may make again
+    Rejected,
+    /// The grantee took over the vault, which the grant cannot be used for again
+    Consumed,
+}
+
+impl Access {
+    /// The name the access is stored under in the `access` column
+    pub fn name(self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Takeover => "takeover",
+        }
+    }
+
+    /// Returns the access stored under a name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The `access` column of a grant
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Access::Read),
+            "takeover" => Some(Access::Takeover),
+            _ => None
+        }
+    }
+}
+
+impl Status {
+    /// The name the status is stored under in the `status` column
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Idle => "idle",
+            Status::Requested => "requested",
+            Status::Approved => "approved",
+            Status::Rejected => "rejected",
+            Status::Consumed => "consumed",
+        }
+    }
+
+    /// Returns the status stored under a name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The `status` column of a grant
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "idle" => Some(Status::Idle),
+            "requested" => Some(Status::Requested),
+            "approved" => Some(Status::Approved),
+            "rejected" => Some(Status::Rejected),
+            "consumed" => Some(Status::Consumed),
+            _ => None
+        }
+    }
+}
+
+/// Represents a grant of emergency access to an owner's vault for a grantee.
+/// Listed to either party without the wrapped key
+#[derive(Serialize)]
+pub struct Grant {
+    /// The id of the grant
+    pub id: i64,
+    /// The id of the owner's row
+    #[serde(skip)]
+    pub owner_id: i64,
+    /// The id of the grantee's row
+    #[serde(skip)]
+    pub grantee_id: i64,
+    /// Whether the User the grant is listed to is its owner rather than its grantee
+    pub owned: bool,
+    /// What the grantee may do once the grant is approved
+    pub access: Access,
+    /// How long the owner has to reject a request before it is approved, in seconds
+    pub wait_seconds: i64,
+    /// Where the grant stands
+    pub status: Status,
+    /// When access was last requested as seconds since the Unix epoch
+    pub requested_at: Option<i64>,
+    /// When the grant was made as seconds since the Unix epoch
+    pub created_at: Option<i64>,
+}
+
+/// The columns a grant is read from, in the order `from_row` expects
+const COLUMNS: &str = "id, owner_id, grantee_id, access, wait_seconds, status, requested_at, created_at";
+
+impl Grant {
+    /// Reads a grant from a row of `COLUMNS`
+    ///
+    /// # Arguments
+    ///
+    /// * `row` - The row to read
+    /// * `user_id` - The id of the User the grant is read for
+    fn from_row(row: &Row, user_id: i64) -> Result<Self, UserError> {
+        let owner_id = row.get(1).map_err(|_| UserError::Corrupt)?;
+        let access: String = row.get(3).map_err(|_| UserError::Corrupt)?;
+        let status: String = row.get(5).map_err(|_| UserError::Corrupt)?;
+        Ok(Grant {
+            id: row.get(0).map_err(|_| UserError::Corrupt)?,
+            owner_id,
+            grantee_id: row.get(2).map_err(|_| UserError::Corrupt)?,
+            owned: owner_id == user_id,
+            access: Access::from_name(&access).ok_or(UserError::Corrupt)?,
+            wait_seconds: row.get(4).map_err(|_| UserError::Corrupt)?,
+            status: Status::from_name(&status).ok_or(UserError::Corrupt)?,
+            requested_at: row.get(6).map_err(|_| UserError::Corrupt)?,
+            created_at: row.get(7).map_err(|_| UserError::Corrupt)?,
+        })
+    }
+}
+
+/// Lists the grants a User owns or was granted
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn list(conn: &Connection, user_id: i64) -> Result<Vec<Grant>, UserError> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM emergency_grants WHERE owner_id = ? OR grantee_id = ? ORDER BY id", COLUMNS))
+        .map_err(|_| UserError::Internal)?;
+    let mut rows = stmt.query(params![user_id, user_id]).map_err(|_| UserError::Internal)?;
+    let mut grants = Vec::new();
+    while let Some(row) = rows.next().map_err(|_| UserError::Internal)? {
+        grants.push(Grant::from_row(row, user_id)?);
+    }
+    Ok(grants)
+}
+
+/// Fetches a grant the User owns or was granted
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `id` - The id of the grant
+pub fn get(conn: &Connection, user_id: i64, id: i64) -> Result<Grant, UserError> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM emergency_grants WHERE id = ? AND (owner_id = ? OR grantee_id = ?)", COLUMNS))
+        .map_err(|_| UserError::Internal)?;
+    let mut rows = stmt.query(params![id, user_id, user_id]).map_err(|_| UserError::Internal)?;
+    let row = rows.next().map_err(|_| UserError::Internal)?.ok_or(UserError::NotFound)?;
+    Grant::from_row(row, user_id)
+}
+
+/// Fetches the owner's vault key wrapped to the grantee's public key
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `id` - The id of the grant
+pub fn wrapped_key(conn: &Connection, id: i64) -> Result<Vec<u8>, UserError> {
+    conn.query_row("SELECT wrapped_key FROM emergency_grants WHERE id = ?", params![id], |row| row.get(0))
+        .map_err(|_| UserError::Corrupt)
+}
+
+/// Stores a new idle grant, returning its id
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `owner_id` - The id of the owner's row
+/// * `grantee_id` - The id of the grantee's row
+/// * `access` - What the grantee may do once the grant is approved
+/// * `wait_seconds` - How long the owner has to reject a request
+/// * `wrapped_key` - The owner's vault key wrapped to the grantee's public key
+/// * `created_at` - The current time as seconds since the Unix epoch
+pub fn insert(conn: &Connection, owner_id: i64, grantee_id: i64, access: Access, wait_seconds: i64,
+    wrapped_key: &[u8], created_at: i64) -> Result<i64, UserError> {
+    let mut stmt = conn.prepare("INSERT INTO emergency_grants (owner_id, grantee_id, access, wait_seconds, wrapped_key, \
+        status, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)")
+        .map_err(|_| UserError::Internal)?;
+    stmt.insert(params![owner_id, grantee_id, access.name(), wait_seconds, wrapped_key, Status::Idle.name(), created_at])
+        .map_err(|_| UserError::Internal)
+}
+
+/// Moves a grant from one of the expected statuses to a new one, recording when access was requested
+/// if given. Fails with `InvalidGrant` if the grant is in none of the expected statuses
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `id` - The id of the grant
+/// * `from` - The statuses the grant may be in
+/// * `to` - The new status of the grant
+/// * `requested_at` - When access was requested, kept as it is if `None`
+pub fn set_status(conn: &Connection, id: i64, from: &[Status], to: Status, requested_at: Option<i64>) -> Result<(), UserError> {
+    // The names are fixed, so they are written into the statement rather than bound one by one
+    let from: Vec<String> = from.iter().map(|status| format!("'{}'", status.name())).collect();
+    let result = conn.execute(&format!("UPDATE emergency_grants SET status = ?, requested_at = COALESCE(?, requested_at) \
+        WHERE id = ? AND status IN ({})", from.join(", ")), params![to.name(), requested_at, id]);
+
+    match result {
+        Ok(1) => Ok(()),
+        Ok(_) => Err(UserError::InvalidGrant),
+        Err(_) => Err(UserError::Internal)
+    }
+}
+
+/// Approves every request whose waiting period has run out, returning the number approved
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `now` - The current time as seconds since the Unix epoch
+pub fn approve_expired(conn: &Connection, now: i64) -> Result<usize, UserError> {
+    conn.execute("UPDATE emergency_grants SET status = ? WHERE status = ? AND requested_at + wait_seconds <= ?",
+        params![Status::Approved.name(), Status::Requested.name(), now])
+        .map_err(|_| UserError::Internal)
+}
+
+/// Removes a grant the User owns
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `owner_id` - The id of the owner's row
+/// * `id` - The id of the grant
+pub fn delete(conn: &Connection, owner_id: i64, id: i64) -> Result<(), UserError> {
+    match conn.execute("DELETE FROM emergency_grants WHERE owner_id = ? AND id = ?", params![owner_id, id]) {
+        Ok(1) => Ok(()),
+        Ok(_) => Err(UserError::NotFound),
+        Err(_) => Err(UserError::Internal)
+    }
+}
+
+/// Removes every grant a User owns
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `owner_id` - The id of the owner's row
+pub fn delete_owned(conn: &Connection, owner_id: i64) -> Result<(), UserError> {
+    conn.execute("DELETE FROM emergency_grants WHERE owner_id = ?", params![owner_id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
+
+/// Removes every grant a User owns or was granted
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn delete_all(conn: &Connection, user_id: i64) -> Result<(), UserError> {
+    conn.execute("DELETE FROM emergency_grants WHERE owner_id = ? OR grantee_id = ?", params![user_id, user_id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
@@ -0,0 +1,87 @@
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use crate::crypto::hash::hash;
+use super::{audit::AuditEvent, user::UserError};
+
+/// The number of bytes of a public key's SHA-256 shown as its fingerprint
+const FINGERPRINT_LEN: usize = 8;
+
+/// Represents whether a User's vault key is escrowed, as shown to the User
+#[derive(Serialize)]
+pub struct EscrowStatus {
+    /// Whether the server wraps every vault key to an escrow public key
+    pub enabled: bool,
+    /// The fingerprint of the escrow public key the server wraps vault keys to, if enabled
+    pub server_fingerprint: Option<String>,
+    /// The fingerprint of the escrow public key this User's vault key is wrapped to, if any
+    pub fingerprint: Option<String>,
+    /// When this User's vault key was wrapped as seconds since the Unix epoch
+    pub escrowed_at: Option<i64>,
+    /// Every recorded wrap and use of this User's escrow
+    pub events: Vec<AuditEvent>,
+}
+
+/// Represents a User's vault key wrapped to an escrow public key
+pub struct Escrow {
+    /// The escrow public key the vault key is wrapped to
+    pub public_key: Vec<u8>,
+    /// The vault key wrapped to the public key
+    pub wrapped_key: Vec<u8>,
+    /// When the vault key was wrapped as seconds since the Unix epoch
+    pub created_at: Option<i64>,
+}
+
+/// The hex encoded start of a public key's SHA-256, which officers compare against their private key's
+///
+/// # Arguments
+///
+/// * `public_key` - The public key
+pub fn fingerprint(public_key: &[u8]) -> String {
+    hex::encode(&hash(public_key)[..FINGERPRINT_LEN])
+}
+
+/// Fetches the escrow of a User, `None` if the vault key is not escrowed
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn get(conn: &Connection, user_id: i64) -> Result<Option<Escrow>, UserError> {
+    let mut stmt = conn.prepare("SELECT public_key, wrapped_key, created_at FROM escrow WHERE user_id = ?")
+        .map_err(|_| UserError::Internal)?;
+    let mut rows = stmt.query(params![user_id]).map_err(|_| UserError::Internal)?;
+    match rows.next().map_err(|_| UserError::Internal)? {
+        Some(row) => Ok(Some(Escrow {
+            public_key: row.get(0).map_err(|_| UserError::Corrupt)?,
+            wrapped_key: row.get(1).map_err(|_| UserError::Corrupt)?,
+            created_at: row.get(2).map_err(|_| UserError::Corrupt)?,
+        })),
+        None => Ok(None)
+    }
+}
+
+/// Stores the escrow of a User, replacing any earlier one
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `escrow` - The wrapped vault key and the public key it is wrapped to
+pub fn store(conn: &Connection, user_id: i64, escrow: &Escrow) -> Result<(), UserError> {
+    conn.execute("INSERT OR REPLACE INTO escrow (user_id, public_key, wrapped_key, created_at) VALUES (?, ?, ?, ?)",
+        params![user_id, escrow.public_key, escrow.wrapped_key, escrow.created_at])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
+
+/// Removes the escrow of a User
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn delete(conn: &Connection, user_id: i64) -> Result<(), UserError> {
+    conn.execute("DELETE FROM escrow WHERE user_id = ?", params![user_id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
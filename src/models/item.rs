@@ -0,0 +1,220 @@
+use serde::{Serialize, Deserialize};
+use crate::crypto::secret::SecretString;
+use super::credentials::CredentialError;
+
+/// The fewest digits a payment card number may have
+const MIN_CARD_DIGITS: usize = 12;
+/// The most digits a payment card number may have
+const MAX_CARD_DIGITS: usize = 19;
+/// The most bytes a Wi-Fi network name may have
+const MAX_SSID_LEN: usize = 32;
+/// The earliest year a payment card may expire
+const MIN_EXPIRY_YEAR: u16 = 2000;
+/// The latest year a payment card may expire
+const MAX_EXPIRY_YEAR: u16 = 2099;
+
+/// Represents what a Credential holds, stored under a `type` field beside the Credential's name
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Item {
+    /// A username and password for a website or application
+    Login(Login),
+    /// Free text such as recovery instructions
+    Note(Note),
+    /// A payment card
+    Card(Card),
+    /// A person's contact and identity details
+    Identity(Identity),
+    /// A key for an API
+    ApiKey(ApiKey),
+    /// A Wi-Fi network
+    Wifi(Wifi),
+}
+
+/// Represents the `type` field of an Item
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemType {
+    /// A Login
+    Login,
+    /// A Note
+    Note,
+    /// A Card
+    Card,
+    /// An Identity
+    Identity,
+    /// An ApiKey
+    ApiKey,
+    /// A Wifi network
+    Wifi,
+}
+
+/// Represents a username and password for a website or application, the only item of vaults
+/// saved before items had types
+#[derive(Serialize)]
+pub struct Login {
+    /// The url of the website or application
+    pub url: String,
+    /// The username
+    pub username: SecretString,
+    /// The password
+    pub password: SecretString,
+}
+
+/// Represents free text such as recovery instructions
+#[derive(Serialize)]
+pub struct Note {
+    /// The text of the note
+    pub text: SecretString,
+}
+
+/// Represents a payment card
+#[derive(Serialize)]
+pub struct Card {
+    /// The name printed on the card
+    pub cardholder: String,
+    /// The card number, digits optionally grouped by spaces or hyphens
+    pub number: SecretString,
+    /// The month the card expires, from 1 to 12
+    pub expiry_month: u8,
+    /// The year the card expires, in four digits
+    pub expiry_year: u16,
+    /// The security code on the back of the card, empty if not kept
+    pub cvv: SecretString,
+}
+
+/// Represents a person's contact and identity details, any of which may be empty
+#[derive(Serialize)]
+pub struct Identity {
+    /// The person's given name
+    pub first_name: String,
+    /// The person's family name
+    pub last_name: String,
+    /// The person's email address
+    pub email: String,
+    /// The person's phone number
+    pub phone: String,
+    /// The person's postal address
+    pub address: String,
+    /// The number of the person's passport, licence or other identity document
+    pub document_number: SecretString,
+}
+
+/// Represents a key for an API
+#[derive(Serialize)]
+pub struct ApiKey {
+    /// The key, or the public half of a key pair
+    pub key: SecretString,
+    /// The secret half of a key pair, empty if the key stands alone
+    pub secret: SecretString,
+    /// The url of the API
+    pub url: String,
+}
+
+/// Represents the security of a Wi-Fi network
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiSecurity {
+    /// No password
+    Open,
+    /// A WEP key
+    Wep,
+    /// A WPA, WPA2 or WPA3 personal passphrase
+    Wpa,
+}
+
+/// Represents a Wi-Fi network
+#[derive(Serialize)]
+pub struct Wifi {
+    /// The name of the network
+    pub ssid: String,
+    /// The security of the network
+    pub security: WifiSecurity,
+    /// The password of the network, empty for an open network
+    pub password: SecretString,
+}
+
+impl Item {
+    /// Checks the fields of the item against its type, such as a card number against its Luhn check digit
+    pub fn validate(&self) -> Result<(), CredentialError> {
+        let valid = match self {
+            Item::Login(_) | Item::Note(_) => Ok(()),
+            Item::Card(card) => card.validate(),
+            Item::Identity(identity) => identity.validate(),
+            Item::ApiKey(api_key) => if api_key.key.as_bytes().is_empty() { Err(()) } else { Ok(()) },
+            Item::Wifi(wifi) => wifi.validate(),
+        };
+        valid.map_err(|_| CredentialError::InvalidItem)
+    }
+}
+
+impl Card {
+    /// Checks the number has a valid length and Luhn check digit, the expiry month exists,
+    /// the expiry year is in this century and the security code is three or four digits if kept
+    fn validate(&self) -> Result<(), ()> {
+        let digits: Vec<u8> = self.number.as_bytes().iter()
+            .filter(|byte| **byte != b' ' && **byte != b'-')
+            .map(|byte| if byte.is_ascii_digit() { Ok(byte - b'0') } else { Err(()) })
+            .collect::<Result<_, ()>>()?;
+        if digits.len() < MIN_CARD_DIGITS || digits.len() > MAX_CARD_DIGITS || !luhn(&digits) {
+            return Err(());
+        }
+        if !(1..=12).contains(&self.expiry_month) || !(MIN_EXPIRY_YEAR..=MAX_EXPIRY_YEAR).contains(&self.expiry_year) {
+            return Err(());
+        }
+        let cvv = self.cvv.as_bytes();
+        if !cvv.is_empty() && (cvv.len() < 3 || cvv.len() > 4 || !cvv.iter().all(u8::is_ascii_digit)) {
+            return Err(());
+        }
+        Ok(())
+    }
+}
+
+impl Identity {
+    /// Checks the email address has a local part and a domain if given
+    fn validate(&self) -> Result<(), ()> {
+        if self.email.is_empty() {
+            return Ok(());
+        }
+        match self.email.split_once('@') {
+            Some((local, domain)) if !local.is_empty() && !domain.is_empty() && !domain.contains('@') => Ok(()),
+            _ => Err(())
+        }
+    }
+}
+
+impl Wifi {
+    /// Checks the network name fits in an SSID and the password suits the network's security
+    fn validate(&self) -> Result<(), ()> {
+        if self.ssid.is_empty() || self.ssid.len() > MAX_SSID_LEN {
+            return Err(());
+        }
+        let password = self.password.as_bytes();
+        let is_hex = password.iter().all(u8::is_ascii_hexdigit);
+        let valid = match self.security {
+            WifiSecurity::Open => password.is_empty(),
+            // 40 or 104 bit keys, as ASCII or hex
+            WifiSecurity::Wep => matches!(password.len(), 5 | 13) || (matches!(password.len(), 10 | 26) && is_hex),
+            // An ASCII passphrase, or the 256 bit key itself in hex
+            WifiSecurity::Wpa => ((8..=63).contains(&password.len()) && password.is_ascii())
+                || (password.len() == 64 && is_hex),
+        };
+        if valid { Ok(()) } else { Err(()) }
+    }
+}
+
+/// Returns whether digits end with their Luhn check digit
+///
+/// # Arguments
+///
+/// * `digits` - The digits, each from 0 to 9
+fn luhn(digits: &[u8]) -> bool {
+    let sum: u32 = digits.iter().rev().enumerate()
+        .map(|(i, digit)| match (i % 2, *digit as u32 * 2) {
+            (0, _) => *digit as u32,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
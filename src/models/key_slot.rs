@@ -0,0 +1,170 @@
+use rusqlite::{Connection, params};
+use serde::{Serialize, Deserialize};
+use super::user::UserError;
+
+/// The most key files a User may hold at once
+pub const MAX_KEY_FILES: usize = 8;
+
+/// Represents the kinds of secret a key slot unlocks a vault with
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotKind {
+    /// The User's password, kept in the User's own row
+    Password,
+    /// A printable recovery code, replaced whenever it is used
+    RecoveryCode,
+    /// A key file
+    KeyFile,
+    /// A secret split into shares, any threshold of which unlock the vault
+    Shares,
+}
+
+impl SlotKind {
+    /// The name the kind is stored under in the `kind` column
+    pub fn name(self) -> &'static str {
+        match self {
+            SlotKind::Password => "password",
+            SlotKind::RecoveryCode => "recovery_code",
+            SlotKind::KeyFile => "key_file",
+            SlotKind::Shares => "shares",
+        }
+    }
+
+    /// Returns the kind stored under a name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The `kind` column of a key slot
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "password" => Some(SlotKind::Password),
+            "recovery_code" => Some(SlotKind::RecoveryCode),
+            "key_file" => Some(SlotKind::KeyFile),
+            "shares" => Some(SlotKind::Shares),
+            _ => None
+        }
+    }
+}
+
+/// Represents one of the secrets that unlock a User's vault, each holding its own copy
+/// of the vault key. Listed to the User without the wrapped key
+#[derive(Serialize)]
+pub struct KeySlot {
+    /// The id of the slot, `None` for the password slot which cannot be revoked
+    pub id: Option<i64>,
+    /// The kind of secret the slot is unlocked with
+    pub kind: SlotKind,
+    /// When the slot was added as seconds since the Unix epoch, `None` for the password slot
+    pub created_at: Option<i64>,
+}
+
+/// Lists the key slots of a User, the password slot first
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn list(conn: &Connection, user_id: i64) -> Result<Vec<KeySlot>, UserError> {
+    let mut slots = vec![KeySlot { id: None, kind: SlotKind::Password, created_at: None }];
+
+    let mut stmt = conn.prepare("SELECT id, kind, created_at FROM key_slots WHERE user_id = ? ORDER BY id")
+        .map_err(|_| UserError::Internal)?;
+    let mut rows = stmt.query(params![user_id]).map_err(|_| UserError::Internal)?;
+    while let Some(row) = rows.next().map_err(|_| UserError::Internal)? {
+        let kind: String = row.get(1).map_err(|_| UserError::Corrupt)?;
+        slots.push(KeySlot {
+            id: Some(row.get(0).map_err(|_| UserError::Corrupt)?),
+            kind: SlotKind::from_name(&kind).ok_or(UserError::Corrupt)?,
+            created_at: row.get(2).map_err(|_| UserError::Corrupt)?,
+        });
+    }
+    Ok(slots)
+}
+
+/// Fetches the wrapped vault keys of a User's slots of one kind, returning each with its slot id
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `kind` - The kind of slot
+pub fn wrapped_keys(conn: &Connection, user_id: i64, kind: SlotKind) -> Result<Vec<(i64, Vec<u8>)>, UserError> {
+    let mut stmt = conn.prepare("SELECT id, wrapped_key FROM key_slots WHERE user_id = ? AND kind = ?")
+        .map_err(|_| UserError::Internal)?;
+    let mut rows = stmt.query(params![user_id, kind.name()]).map_err(|_| UserError::Internal)?;
+    let mut wrapped_keys = Vec::new();
+    while let Some(row) = rows.next().map_err(|_| UserError::Internal)? {
+        wrapped_keys.push((row.get(0).map_err(|_| UserError::Corrupt)?, row.get(1).map_err(|_| UserError::Corrupt)?));
+    }
+    Ok(wrapped_keys)
+}
+
+/// Counts a User's slots of one kind
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `kind` - The kind of slot
+pub fn count(conn: &Connection, user_id: i64, kind: SlotKind) -> Result<usize, UserError> {
+    conn.query_row("SELECT COUNT(*) FROM key_slots WHERE user_id = ? AND kind = ?",
+        params![user_id, kind.name()], |row| row.get::<_, i64>(0))
+        .map(|count| count as usize)
+        .map_err(|_| UserError::Internal)
+}
+
+/// Stores a new key slot, returning its id
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `kind` - The kind of slot
+/// * `wrapped_key` - The vault key wrapped with the key derived from the slot's secret
+/// * `created_at` - The current time as seconds since the Unix epoch
+pub fn insert(conn: &Connection, user_id: i64, kind: SlotKind, wrapped_key: &[u8], created_at: i64) -> Result<i64, UserError> {
+    let mut stmt = conn.prepare("INSERT INTO key_slots (user_id, kind, wrapped_key, created_at) VALUES (?, ?, ?, ?)")
+        .map_err(|_| UserError::Internal)?;
+    stmt.insert(params![user_id, kind.name(), wrapped_key, created_at]).map_err(|_| UserError::Internal)
+}
+
+/// Removes a User's slots of one kind other than the one to keep
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `kind` - The kind of slot
+/// * `keep` - The id of the slot to keep
+pub fn delete_others(conn: &Connection, user_id: i64, kind: SlotKind, keep: i64) -> Result<(), UserError> {
+    conn.execute("DELETE FROM key_slots WHERE user_id = ? AND kind = ? AND id <> ?", params![user_id, kind.name(), keep])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
+
+/// Removes one of a User's slots
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+/// * `id` - The id of the slot
+pub fn delete(conn: &Connection, user_id: i64, id: i64) -> Result<(), UserError> {
+    match conn.execute("DELETE FROM key_slots WHERE user_id = ? AND id = ?", params![user_id, id]) {
+        Ok(1) => Ok(()),
+        Ok(_) => Err(UserError::NotFound),
+        Err(_) => Err(UserError::Internal)
+    }
+}
+
+/// Removes every slot of a User
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `user_id` - The id of the User's row
+pub fn delete_all(conn: &Connection, user_id: i64) -> Result<(), UserError> {
+    conn.execute("DELETE FROM key_slots WHERE user_id = ?", params![user_id])
+        .map(|_| ())
+        .map_err(|_| UserError::Internal)
+}
@@ -1,2 +1,9 @@
 pub mod user;
-pub mod credentials;
\ No newline at end of file
+pub mod credentials;
+pub mod key_slot;
+pub mod emergency;
+pub mod decoy;
+pub mod device;
+pub mod escrow;
+pub mod audit;
+pub mod item;
\ No newline at end of file
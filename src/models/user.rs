@@ -1,146 +1,1682 @@
-use rusqlite::{Connection, params};
-use super::credentials::{Credentials};
-use crate::crypto::{aead::{aead_seal, aead_open}, hash::{pbkdf2_rand_salt, pbkdf2_verify, hash}};
-use hex;
+use rusqlite::{Connection, ErrorCode, params};
+use super::{credentials::{Credentials}, key_slot::{self, KeySlot, SlotKind, MAX_KEY_FILES},
+    emergency::{self, Access, Grant, Status, SECONDS_PER_DAY}, decoy::{self, DecoyColumns, DuressAction},
+    device::{self, Device, MAX_DEVICES, MAX_PIN_ATTEMPTS, MIN_PIN_LEN}, escrow::{self, Escrow, EscrowStatus},
+    audit::{self, AuditAction}};
+use crate::crypto::{aead::{aead_open, aead_seal_with_key, aead_open_with_key, kek_wrap, kek_unwrap, AeadAlgorithm}, envelope,
+    keyring::KeyRing, keypair, rand::Rng, recovery, shamir::{self, SHARED_SECRET_LEN},
+    hash::{new_master_key, unmatched_password_hash, verify_password, Kdf, MasterKey, PasswordHash}, index::{self, UsernameIndex}, secret::{SecretBytes, SecretString}};
+use crate::config::settings::Settings;
 use std::str;
 
-const PASSWORD_HASH_LEN: usize = 256;
+const VAULT_KEY_LEN: usize = 32;
+/// The initial size of the buffer credentials are serialized into before sealing
+const CREDENTIALS_JSON_CAPACITY: usize = 4096;
+
+/// The version of the context sealed blobs are bound to
+const CONTEXT_VERSION: u8 = 1;
 
 /// Represents a User of this application
 pub struct User {
     id: i64,
-    pub username: String,
-    pub password: String,
-    pub credentials: Credentials
+    /// The keyed index of this User's name, which this User is looked up by
+    index: UsernameIndex,
+    pub credentials: Credentials,
+    /// The AEAD algorithm this User's data is sealed with
+    algorithm: AeadAlgorithm,
+    /// Whether this User's data is sealed in envelopes that commit to their key.
+    /// Once set it is never cleared
+    key_commitment: bool,
+    /// The random key this User's data is sealed with, stored wrapped by the encryption subkey
+    /// of the password's master key
+    vault_key: SecretBytes,
+    /// The number of times this User's data has been saved, bound to the sealed data
+    revision: i64,
+    /// The duress action of the decoy if this User was opened with the duress password,
+    /// in which case the credentials and vault key are the decoy's
+    decoy: Option<DuressAction>
 }
 
+/// Represents the reasons an operation on a User can fail
+#[derive(Debug, PartialEq)]
+pub enum UserError {
+    /// The username is malformed or taken by another User
+    InvalidUsername,
+    /// No User has the username, or the password is incorrect
+    InvalidCredentials,
+    /// The password is correct but the stored data was not sealed for this User's row
+    /// or revision, having been transplanted from another row or rolled back
+    ContextMismatch,
+    /// The User was saved by another request since it was fetched
+    StaleRevision,
+    /// The requested credential does not exist
+    NotFound,
+    /// A stored blob or the decrypted vault of the User is malformed
+    Corrupt,
+    /// The key slot cannot be added or revoked
+    InvalidSlot,
+    /// The confirmation typed by the User does not match
+    InvalidConfirmation,
+    /// The emergency access grant does not allow the operation in its current state
+    InvalidGrant,
+    /// Duress passwords are disabled on this server, or the duress password repeats the password
+    DuressUnavailable,
+    /// The device cannot be enrolled, its PIN being too short or the User having too many devices
+    InvalidDevice,
+    /// The fields of a credential do not suit its type, such as a card number failing its Luhn check
+    InvalidItem,
+    /// The User could not be sealed or saved
+    Internal
+}
 
 impl User {
 
-    /// Creates a new User in the database, returning the new User
+    /// Creates a new User in the database along with a recovery code slot,
+    /// returning the new User and its recovery code
     ///
     /// # Arguments
     ///
     /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
     /// * `username` - The name of the new User
     /// * `password` - The password of the new User, used for encryption of credentials
-    pub fn create(conn: &Connection, username: String, password: String) -> Result<Self, ()> {
+    pub fn create(conn: &mut Connection, settings: &Settings, username: String, password: SecretString) -> Result<(Self, SecretString), UserError> {
+
+        check_username_free(conn, settings, &username, None)?;
+
+        let index = index::index(settings.index_keys.current(), &username);
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
 
-        if username.contains(":") {
-            return Err(());
+        // The row id is bound to the sealed data, so the row is inserted before sealing
+        let mut stmt = transaction.prepare("INSERT INTO users (hash, index_key) VALUES (?, ?)").map_err(|_| UserError::Internal)?;
+        let id = stmt.insert(params![index.hash, index.key_id]).map_err(username_taken)?;
+        drop(stmt);
+
+        let user = User {
+            id,
+            index,
+            credentials: Credentials::new(),
+            algorithm: settings.aead_algorithm,
+            key_commitment: settings.key_commitment,
+            vault_key: SecretBytes::random(settings.rng.as_ref(), VAULT_KEY_LEN).map_err(|_| UserError::Internal)?,
+            revision: 0,
+            decoy: None
+        };
+        user.save_with_password(&transaction, settings, &password)?;
+        user.ensure_keypair(&transaction, settings)?;
+        user.ensure_escrow(&transaction, settings)?;
+        if settings.duress {
+            decoy::store(&transaction, id, &seal_filler_decoy(settings, id)?)?;
         }
+        let (_, recovery_code) = user.add_slot(&transaction, settings, SlotKind::RecoveryCode)?;
+
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok((User { revision: 1, ..user }, recovery_code))
+    }
 
-        let username_hash = hex::encode(hash(username.as_bytes()));
+    /// Fetches an existing user, returning the User if found.
+    /// Rows hashed with an outdated KDF or weaker parameters are rehashed with the configured ones,
+    /// rows sealed directly with the password are given a vault key,
+    /// rows are resealed in committing envelopes once key commitment is enabled,
+    /// rows are rewrapped with the current key-encryption key if one is configured,
+    /// rows stored before one was configured are wrapped if `kek_migrate` is set and refused otherwise,
+    /// rows indexed under an older index key are indexed under the current one,
+    /// credentials saved before credentials had ids are given ids,
+    /// rows without a keypair for emergency access are given one,
+    /// and vault keys are wrapped to the escrow public key if one is configured and they are not yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `password` - The password of the User
+    pub fn login(conn: &Connection, settings: &Settings, username: String, password: SecretString) -> Result<Self, UserError> {
+        let stored_index = find_index(conn, settings, &username)?;
 
-        let mut stmt = conn.prepare("SELECT * FROM users WHERE hash = ?").unwrap();
-        if stmt.exists(params![username_hash]).unwrap() {
-            return Err(());
+        let mut stmt = conn.prepare("SELECT id, hash, password, salt, vault_key, data, revision FROM users \
+            WHERE hash = ? AND index_key IS ?").map_err(|_| UserError::Internal)?;
+
+        let mut rows = stmt.query(params![stored_index.hash, stored_index.key_id]).map_err(|_| UserError::Internal)?;
+        let row = rows.next().map_err(|_| UserError::Internal)?.ok_or(UserError::InvalidCredentials)?;
+
+        let id: i64 = row.get(0).map_err(|_| UserError::Corrupt)?;
+        let password_hash: Vec<u8> = row.get(2).map_err(|_| UserError::Corrupt)?;
+        let salt: Option<Vec<u8>> = row.get(3).map_err(|_| UserError::Corrupt)?;
+        let wrapped_key: Option<Vec<u8>> = row.get(4).map_err(|_| UserError::Corrupt)?;
+        let data: Vec<u8> = row.get(5).map_err(|_| UserError::Corrupt)?;
+        let revision: Option<i64> = row.get(6).map_err(|_| UserError::Corrupt)?;
+
+        // Every blob is unwrapped before use if it was wrapped with a key-encryption key. Unwrapped blobs
+        // are only accepted while migrating, as they are wrapped below once the password is verified
+        let keks = settings.keks.as_deref();
+        let migrate = settings.kek_migrate;
+        let (password_hash, password_kek) = kek_unwrap(&password_hash, keks, migrate, &kek_context(id, "password"))
+            .map_err(|_| UserError::Internal)?;
+        let (data, data_kek) = kek_unwrap(&data, keks, migrate, &kek_context(id, "data"))
+            .map_err(|_| UserError::Internal)?;
+        let (wrapped_key, key_kek) = match wrapped_key {
+            Some(wrapped_key) => {
+                let (wrapped_key, key_kek) = kek_unwrap(&wrapped_key, keks, migrate, &kek_context(id, "vault_key"))
+                    .map_err(|_| UserError::Internal)?;
+                (Some(wrapped_key), key_kek)
+            },
+            None => (None, None)
+        };
+        let current_kek = keks.map(|keks| keks.current().id);
+        let kek_stale = [password_kek, data_kek, key_kek].iter().any(|kek| *kek != current_kek);
+
+        let password_hash = parse_password_hash(password_hash, salt).map_err(|_| UserError::Corrupt)?;
+
+        let verified = verify_password(password.as_bytes(), &password_hash);
+        // With duress passwords enabled the decoy's hash is tried on every login, whether or not the password
+        // matched, and rows without a decoy hold filler, so the time taken tells nothing about the decoy
+        let decoy_key = if settings.duress {
+            User::verify_decoy(conn, settings, id, &password)?
+        } else {
+            None
+        };
+        let master_key = match (verified, decoy_key) {
+            (Ok(master_key), _) => master_key,
+            (Err(_), Some((master_key, columns))) =>
+                return User::open_decoy(conn, settings, id, stored_index, revision, &data, &master_key, columns),
+            (Err(_), None) => return Err(UserError::InvalidCredentials)
+        };
+
+        let data_header = envelope::parse(&data).map_err(|_| UserError::Corrupt)?.header;
+
+        // Rows saved since blobs were bound to their context must not hold unbound blobs
+        if revision.is_some() && data_header.version < 2 {
+            return Err(UserError::ContextMismatch);
         }
 
-        let mut password_hash = [0u8; PASSWORD_HASH_LEN];
-        let salt = pbkdf2_rand_salt(password.as_bytes(), &mut password_hash);
+        let (vault_key, credentials_json) = match &wrapped_key {
+            Some(wrapped_key) => {
+                // A row's blobs are committed together, so a lone uncommitted blob was swapped in
+                let key_header = envelope::parse(wrapped_key).map_err(|_| UserError::Corrupt)?.header;
+                if key_header.is_committed() != data_header.is_committed() {
+                    return Err(UserError::ContextMismatch);
+                }
+
+                let vault_key = match &master_key {
+                    Some(master_key) => aead_open_with_key(wrapped_key,
+                        master_key.encryption_key().map_err(|_| UserError::Internal)?.as_bytes(), &key_context(id)),
+                    // Keys wrapped before master keys were split into subkeys need a second KDF run
+                    None => aead_open(wrapped_key, password.as_bytes(), &password_hash.kdf, &key_context(id))
+                }.map_err(|_| UserError::ContextMismatch)?;
+                let credentials_json = aead_open_with_key(&data, vault_key.as_bytes(),
+                    &data_context(id, &stored_index.hash, revision.unwrap_or(0)))
+                    .map_err(|_| UserError::ContextMismatch)?;
+                (vault_key, credentials_json)
+            },
+            None => (
+                SecretBytes::random(settings.rng.as_ref(), VAULT_KEY_LEN).map_err(|_| UserError::Internal)?,
+                aead_open(&data, password.as_bytes(), &password_hash.kdf, &[]).map_err(|_| UserError::ContextMismatch)?
+            )
+        };
+
+        let mut credentials = Credentials::decode(credentials_json.as_bytes()).map_err(|_| UserError::Corrupt)?;
+        let ids_assigned = credentials.assign_ids(settings.rng.as_ref()).map_err(|_| UserError::Internal)?;
+
+        let mut user = User {
+            id,
+            index: index::index(settings.index_keys.current(), &username),
+            credentials,
+            algorithm: data_header.algorithm,
+            key_commitment: data_header.is_committed() || settings.key_commitment,
+            vault_key,
+            revision: revision.unwrap_or(0),
+            decoy: None
+        };
+
+        if revision.is_none() || wrapped_key.is_none() || master_key.is_none() || password_hash.kdf.is_weaker_than(&settings.kdf)
+            || user.key_commitment != data_header.is_committed() || kek_stale {
+            user.save_with_password(conn, settings, &password)?;
+            user.revision += 1;
+        } else if user.index != stored_index || ids_assigned {
+            user.save(conn, settings)?;
+        }
+        user.ensure_keypair(conn, settings)?;
+        user.ensure_escrow(conn, settings)?;
 
-        let user_pass_pairs = Credentials::new();
-        let user_pass_pairs_json = serde_json::to_string(&user_pass_pairs).unwrap();
-        let data = aead_seal(&user_pass_pairs_json.into_bytes(), password.as_bytes());
+        Ok(user)
+    }
 
-        if data.is_err() {
-            return Err(());
+    /// Changes the password of an existing User, returning the User. The password hash and the
+    /// wrapped vault key are replaced together with the data in one transaction, so either the old
+    /// or the new password works whenever the change is interrupted. The credentials are resealed
+    /// under the next revision but not with a new vault key, so the other key slots keep working
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `password` - The current password of the User
+    /// * `new_password` - The new password of the User
+    pub fn change_password(conn: &mut Connection, settings: &Settings, username: String, password: SecretString,
+        new_password: SecretString) -> Result<Self, UserError> {
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        let mut user = User::login(&transaction, settings, username, password)?;
+        user.save_with_password(&transaction, settings, &new_password)?;
+        user.revision += 1;
+        // Devices were enrolled with the old password, so they are enrolled again with the new one
+        if user.decoy.is_none() {
+            device::delete_all(&transaction, user.id)?;
         }
 
-        let mut stmt = conn.prepare("INSERT INTO users (hash, password, salt, data) VALUES (?1, ?2, ?3, ?4)").unwrap();
-        let id = stmt.insert(params![username_hash, password_hash.to_vec(), salt, data.unwrap()]);
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok(user)
+    }
 
-        if id.is_err() {
-            return Err(());
+    /// Renames an existing User, returning the User. The credentials are resealed since they
+    /// are bound to the index of the name. Two Users renamed to the same name at once are told
+    /// apart by the unique index on the `hash` column
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The current name of the User
+    /// * `password` - The password of the User
+    /// * `new_username` - The new name of the User
+    pub fn rename(conn: &mut Connection, settings: &Settings, username: String, password: SecretString,
+        new_username: String) -> Result<Self, UserError> {
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        let mut user = User::login(&transaction, settings, username, password)?;
+        check_username_free(&transaction, settings, &new_username, Some(user.id))?;
+        // The name belongs to the real vault as well, so a decoy is answered as if it were renamed
+        if !user.is_decoy() {
+            user.index = index::index(settings.index_keys.current(), &new_username);
+            user.save(&transaction, settings)?;
         }
-        
-        Ok(User {
-            id: id.unwrap(),
-            username: username,
-            password: password,
-            credentials: user_pass_pairs
-        })
+
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok(user)
     }
 
-    /// Fetches an existing user, returning the User if found
+    /// Deletes an existing User along with every row it owns. The confirmation must repeat the username.
+    /// A decoy only deletes itself, leaving filler in its place so the duress password stops working
     ///
     /// # Arguments
     ///
     /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
     /// * `username` - The name of the User
     /// * `password` - The password of the User
-    pub fn login(conn: &Connection, username: String, password: String) -> Result<Self, ()> {
-        let username_hash = hex::encode(hash(username.as_bytes()));
-        let mut stmt = conn.prepare("SELECT id, hash, password, salt, data FROM users WHERE hash = ?").unwrap();
+    /// * `confirmation` - The username typed again by the User
+    pub fn delete_account(conn: &mut Connection, settings: &Settings, username: String, password: SecretString,
+        confirmation: &str) -> Result<(), UserError> {
+        if confirmation != username {
+            return Err(UserError::InvalidConfirmation);
+        }
+
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        let user = User::login(&transaction, settings, username, password)?;
+        if user.is_decoy() {
+            decoy::store(&transaction, user.id, &seal_filler_decoy(settings, user.id)?)?;
+            return transaction.commit().map_err(|_| UserError::Internal);
+        }
+        key_slot::delete_all(&transaction, user.id)?;
+        emergency::delete_all(&transaction, user.id)?;
+        device::delete_all(&transaction, user.id)?;
+        escrow::delete(&transaction, user.id)?;
+        audit::delete_all(&transaction, user.id)?;
+        let deleted = transaction.execute("DELETE FROM users WHERE id = ? AND revision IS ?",
+            params![user.id, user.revision_column()]);
+        match deleted {
+            Ok(1) => (),
+            Ok(_) => return Err(UserError::StaleRevision),
+            Err(_) => return Err(UserError::Internal)
+        }
+
+        transaction.commit().map_err(|_| UserError::Internal)
+    }
+
+    /// Registers a duress password for an existing User, returning the User. Logging in with the duress
+    /// password opens a new empty decoy vault, sealed apart from the real one, and takes the duress action
+    /// on the real vault. Replaces any earlier duress password along with its decoy. A decoy is answered
+    /// as if it registered one, leaving the decoy as it is
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `password` - The password of the User
+    /// * `duress_password` - The password opening the decoy vault
+    /// * `action` - What happens to the real vault when the duress password is used
+    pub fn set_duress_password(conn: &mut Connection, settings: &Settings, username: String, password: SecretString,
+        duress_password: SecretString, action: DuressAction) -> Result<Self, UserError> {
+        if !settings.duress || duress_password.as_bytes() == password.as_bytes() {
+            return Err(UserError::DuressUnavailable);
+        }
+
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        let user = User::login(&transaction, settings, username, password)?;
+        let vault_key = SecretBytes::random(settings.rng.as_ref(), VAULT_KEY_LEN).map_err(|_| UserError::Internal)?;
+        let columns = seal_decoy(settings, user.id, &duress_password, &vault_key, action, &Credentials::new())?;
+        if !user.is_decoy() {
+            decoy::store(&transaction, user.id, &columns)?;
+        }
+
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok(user)
+    }
+
+    /// Removes the duress password of this User along with its decoy, leaving filler in its place.
+    /// A decoy is answered as if it removed it, leaving the decoy as it is
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    pub fn clear_duress_password(&self, conn: &Connection, settings: &Settings) -> Result<(), UserError> {
+        if !settings.duress {
+            return Err(UserError::DuressUnavailable);
+        }
+        if self.is_decoy() {
+            return Ok(());
+        }
+        decoy::store(conn, self.id, &seal_filler_decoy(settings, self.id)?)
+    }
+
+    /// Fetches an existing user with one of its key files instead of its password, returning the User if found
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `key_file` - The base64 encoded key file
+    pub fn unlock_with_key_file(conn: &Connection, settings: &Settings, username: String, key_file: &SecretString) -> Result<Self, UserError> {
+        let key_file = recovery::decode_key_file(key_file.as_bytes()).map_err(|_| UserError::InvalidCredentials)?;
+        let (mut user, ids_assigned) = User::open_slot(conn, settings, &username, SlotKind::KeyFile, key_file.as_bytes())?;
+        user.reindex(conn, settings, &username, ids_assigned)?;
+        Ok(user)
+    }
+
+    /// Fetches an existing user with an enrolled device's secret and PIN instead of its password,
+    /// returning the User if found. An attempt is counted before the PIN is checked, and the device
+    /// is removed along with its wrapped key once `MAX_PIN_ATTEMPTS` wrong PINs were tried in a row
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `device_id` - The id of the device
+    /// * `device_secret` - The base64 encoded secret stored on the device
+    /// * `pin` - The PIN of the device
+    pub fn unlock_with_device(conn: &Connection, settings: &Settings, username: String, device_id: i64,
+        device_secret: &SecretString, pin: &SecretString) -> Result<Self, UserError> {
+        let stored_index = find_index(conn, settings, &username)?;
+        let id: i64 = conn.query_row("SELECT id FROM users WHERE hash = ? AND index_key IS ?",
+            params![stored_index.hash, stored_index.key_id], |row| row.get(0))
+            .map_err(|_| UserError::Internal)?;
+
+        let (password_hash, wrapped_key) = device::keys(conn, id, device_id)?;
+        let attempts = device::reserve_attempt(conn, id, device_id)?;
+        let password_hash = parse_password_hash(password_hash, None).map_err(|_| UserError::Corrupt)?;
+        let master_key = recovery::decode_key_file(device_secret.as_bytes())
+            .and_then(|device_secret| verify_password(device_password(&device_secret, pin).as_bytes(), &password_hash));
+        let master_key = match master_key {
+            Ok(Some(master_key)) => master_key,
+            _ => {
+                if attempts >= MAX_PIN_ATTEMPTS {
+                    device::delete(conn, id, device_id)?;
+                }
+                return Err(UserError::InvalidCredentials);
+            }
+        };
+        device::reset_attempts(conn, device_id, settings.clock.unix_seconds())?;
+
+        let encryption_key = master_key.encryption_key().map_err(|_| UserError::Internal)?;
+        let vault_key = aead_open_with_key(&wrapped_key, encryption_key.as_bytes(), &device_context(id))
+            .map_err(|_| UserError::ContextMismatch)?;
+        let (mut user, ids_assigned) = User::open_row(conn, settings, id, vault_key)?;
+        user.reindex(conn, settings, &username, ids_assigned)?;
+        Ok(user)
+    }
+
+    /// Fetches an existing user with its recovery code and sets a new password.
+    /// The recovery code is used up, so a new one is returned along with the User
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `recovery_code` - The recovery code of the User
+    /// * `password` - The new password of the User
+    pub fn recover(conn: &mut Connection, settings: &Settings, username: String, recovery_code: &SecretString,
+        password: SecretString) -> Result<(Self, SecretString), UserError> {
+        let code = str::from_utf8(recovery_code.as_bytes()).map_err(|_| UserError::InvalidCredentials)?;
+        let code = recovery::decode_recovery_code(code).map_err(|_| UserError::InvalidCredentials)?;
+
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        let user = User::reset_password(&transaction, settings, &username, SlotKind::RecoveryCode, code.as_bytes(), &password)?;
+        let (_, recovery_code) = user.add_slot(&transaction, settings, SlotKind::RecoveryCode)?;
+
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok((user, recovery_code))
+    }
+
+    /// Fetches an existing user with enough shares of its split secret and sets a new password in one
+    /// transaction, so the old password keeps working if the recovery is interrupted.
+    /// The shares keep working until the slot is revoked or the secret is split again
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `shares` - At least the threshold of shares of the User's split secret
+    /// * `password` - The new password of the User
+    pub fn recover_with_shares(conn: &mut Connection, settings: &Settings, username: String, shares: &[SecretString],
+        password: SecretString) -> Result<Self, UserError> {
+        let shares = shares.iter()
+            .map(|share| shamir::decode_share(str::from_utf8(share.as_bytes()).map_err(|_| ())?))
+            .collect::<Result<Vec<_>, ()>>()
+            .map_err(|_| UserError::InvalidCredentials)?;
+        let secret = shamir::combine(&shares).map_err(|_| UserError::InvalidCredentials)?;
+
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        let user = User::reset_password(&transaction, settings, &username, SlotKind::Shares, secret.as_bytes(), &password)?;
+
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok(user)
+    }
 
-        let rows = stmt.query(params![username_hash]);
+    /// Opens the vault of an existing User with the secret of one of its key slots and saves it
+    /// under a new password, indexed under the current index key, returning the User
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `kind` - The kind of slot the secret belongs to
+    /// * `secret` - The bytes of the slot's secret
+    /// * `password` - The new password of the User
+    fn reset_password(conn: &Connection, settings: &Settings, username: &str, kind: SlotKind, secret: &[u8],
+        password: &SecretString) -> Result<Self, UserError> {
+        let (mut user, _) = User::open_slot(conn, settings, username, kind, secret)?;
+        user.index = index::index(settings.index_keys.current(), username);
+        user.save_with_password(conn, settings, password)?;
+        user.revision += 1;
+        device::delete_all(conn, user.id)?;
+        Ok(user)
+    }
+
+    /// Opens the vault of an existing User with the secret of one of its key slots, returning the User
+    /// along with whether its credentials were given ids, as `open_row` does
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `kind` - The kind of slot the secret belongs to
+    /// * `secret` - The bytes of the slot's secret
+    fn open_slot(conn: &Connection, settings: &Settings, username: &str, kind: SlotKind, secret: &[u8])
+        -> Result<(Self, bool), UserError> {
+        let stored_index = find_index(conn, settings, username)?;
+        let id: i64 = conn.query_row("SELECT id FROM users WHERE hash = ? AND index_key IS ?",
+            params![stored_index.hash, stored_index.key_id], |row| row.get(0))
+            .map_err(|_| UserError::Internal)?;
+
+        let slot_key = recovery::slot_key(secret).map_err(|_| UserError::Internal)?;
+        let vault_key = key_slot::wrapped_keys(conn, id, kind)?.iter()
+            .find_map(|(_, wrapped_key)| aead_open_with_key(wrapped_key, slot_key.as_bytes(), &slot_context(id, kind)).ok())
+            .ok_or(UserError::InvalidCredentials)?;
+
+        User::open_row(conn, settings, id, vault_key)
+    }
+
+    /// Opens the data of a User's row with its vault key, returning the User. The stored index is kept,
+    /// and the data keeps the commitment it was sealed with since the vault key wrapped by the password
+    /// cannot be resealed without the password. Credentials saved before credentials had ids are given
+    /// ids, returned along with the User as whether any was given so a caller that writes can save them.
+    /// Nothing is written, so a read-only caller leaves the row as it is
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `id` - The id of the User's row
+    /// * `vault_key` - The vault key of the User
+    fn open_row(conn: &Connection, settings: &Settings, id: i64, vault_key: SecretBytes) -> Result<(Self, bool), UserError> {
+        let mut stmt = conn.prepare("SELECT hash, index_key, data, revision FROM users WHERE id = ?")
+            .map_err(|_| UserError::Internal)?;
+        let mut rows = stmt.query(params![id]).map_err(|_| UserError::Internal)?;
+        let row = rows.next().map_err(|_| UserError::Internal)?.ok_or(UserError::NotFound)?;
+
+        let stored_index = UsernameIndex {
+            hash: row.get(0).map_err(|_| UserError::Corrupt)?,
+            key_id: row.get(1).map_err(|_| UserError::Corrupt)?,
+        };
+        let data: Vec<u8> = row.get(2).map_err(|_| UserError::Corrupt)?;
+        let revision: Option<i64> = row.get(3).map_err(|_| UserError::Corrupt)?;
+
+        // Nothing is saved here, so a blob not yet wrapped is refused until a login wraps it
+        let (data, _) = kek_unwrap(&data, settings.keks.as_deref(), false, &kek_context(id, "data"))
+            .map_err(|_| UserError::Internal)?;
+        let data_header = envelope::parse(&data).map_err(|_| UserError::Corrupt)?.header;
+        let credentials_json = aead_open_with_key(&data, vault_key.as_bytes(),
+            &data_context(id, &stored_index.hash, revision.unwrap_or(0)))
+            .map_err(|_| UserError::ContextMismatch)?;
+        let mut credentials = Credentials::decode(credentials_json.as_bytes()).map_err(|_| UserError::Corrupt)?;
+        let ids_assigned = credentials.assign_ids(settings.rng.as_ref()).map_err(|_| UserError::Internal)?;
+
+        let user = User {
+            id,
+            index: stored_index,
+            credentials,
+            algorithm: data_header.algorithm,
+            key_commitment: data_header.is_committed(),
+            vault_key,
+            revision: revision.unwrap_or(0),
+            decoy: None
+        };
+        Ok((user, ids_assigned))
+    }
+
+    /// Tries a password on the decoy of a User, giving the row filler if it has no decoy yet
+    /// and wrapping its blobs if they were stored before key-encryption keys were configured and are being migrated.
+    /// Returns the master key of the duress password along with the decoy if the password matches
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `id` - The id of the User's row
+    /// * `password` - The password to try
+    fn verify_decoy(conn: &Connection, settings: &Settings, id: i64, password: &SecretString)
+        -> Result<Option<(MasterKey, DecoyColumns)>, UserError> {
+        let columns = match decoy::columns(conn, id)? {
+            Some(mut columns) => {
+                if wrap_decoy_columns(settings, id, &mut columns)? {
+                    decoy::store(conn, id, &columns)?;
+                }
+                columns
+            },
+            None => {
+                let columns = seal_filler_decoy(settings, id)?;
+                decoy::store(conn, id, &columns)?;
+                columns
+            }
+        };
 
-        if rows.is_err() {
-            return Err(());
+        let (password_hash, _) = kek_unwrap(&columns.password, settings.keks.as_deref(), false, &kek_context(id, "decoy_password"))
+            .map_err(|_| UserError::Internal)?;
+        let password_hash = parse_password_hash(password_hash, None).map_err(|_| UserError::Corrupt)?;
+        match verify_password(password.as_bytes(), &password_hash) {
+            Ok(Some(master_key)) => Ok(Some((master_key, columns))),
+            _ => Ok(None)
         }
-        let mut rows = rows.unwrap();
+    }
 
-        let row = rows.next().unwrap();
-        if row.is_none() {
-            return Err(());
+    /// Opens the decoy of a User with the master key of its duress password and takes its duress action
+    /// on the real vault, returning the User holding the decoy's credentials. Credentials saved before
+    /// credentials had ids are given ids and saved
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `id` - The id of the User's row
+    /// * `stored_index` - The index the row is stored under
+    /// * `revision` - The `revision` column of the row
+    /// * `data` - The real vault as stored, unwrapped from any key-encryption key
+    /// * `master_key` - The master key of the duress password
+    /// * `columns` - The blobs of the decoy
+    #[allow(clippy::too_many_arguments)]
+    fn open_decoy(conn: &Connection, settings: &Settings, id: i64, stored_index: UsernameIndex, revision: Option<i64>,
+        data: &[u8], master_key: &MasterKey, columns: DecoyColumns) -> Result<Self, UserError> {
+        let keks = settings.keks.as_deref();
+        let (wrapped_key, _) = kek_unwrap(&columns.vault_key, keks, false, &kek_context(id, "decoy_key"))
+            .map_err(|_| UserError::Internal)?;
+        let (decoy_data, _) = kek_unwrap(&columns.data, keks, false, &kek_context(id, "decoy_data"))
+            .map_err(|_| UserError::Internal)?;
+        let decoy_header = envelope::parse(&decoy_data).map_err(|_| UserError::Corrupt)?.header;
+
+        let encryption_key = master_key.encryption_key().map_err(|_| UserError::Internal)?;
+        let vault_key = aead_open_with_key(&wrapped_key, encryption_key.as_bytes(), &decoy_key_context(id))
+            .map_err(|_| UserError::ContextMismatch)?;
+        let decoy_json = aead_open_with_key(&decoy_data, vault_key.as_bytes(), &decoy_data_context(id))
+            .map_err(|_| UserError::ContextMismatch)?;
+        let (action, mut credentials) = decoy::decode(decoy_json.as_bytes())?;
+        let ids_assigned = credentials.assign_ids(settings.rng.as_ref()).map_err(|_| UserError::Internal)?;
+
+        match action {
+            DuressAction::None => (),
+            DuressAction::Lock => lock_vault(conn, settings, id, data)?,
+            DuressAction::Wipe => {
+                lock_vault(conn, settings, id, data)?;
+                key_slot::delete_all(conn, id)?;
+                emergency::delete_owned(conn, id)?;
+                escrow::delete(conn, id)?;
+                // The sealed credentials are left as filler, as nothing holds their vault key any more, but the
+                // keypair goes so no one can grant emergency access to the wiped account
+                conn.execute("UPDATE users SET public_key = NULL, private_key = NULL WHERE id = ?", params![id])
+                    .map_err(|_| UserError::Internal)?;
+            }
         }
 
-        let row = row.unwrap();
+        let mut user = User {
+            id,
+            index: stored_index,
+            credentials,
+            algorithm: decoy_header.algorithm,
+            key_commitment: decoy_header.is_committed(),
+            vault_key,
+            revision: revision.unwrap_or(0),
+            decoy: Some(action)
+        };
+        if ids_assigned {
+            user.save(conn, settings)?;
+        }
+        Ok(user)
+    }
 
-        let (id, password_hash, salt, data): (i64, Vec<u8>, Vec<u8>, Vec<u8>)
-            = (row.get(0).unwrap(), row.get(2).unwrap(), row.get(3).unwrap(), row.get(4).unwrap());
+    /// Indexes this User under the current index key, saving it if it is stored under an older one
+    /// or its credentials were just given ids
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `username` - The name of the User
+    /// * `ids_assigned` - Whether the User's credentials were given ids when opened
+    fn reindex(&mut self, conn: &Connection, settings: &Settings, username: &str, ids_assigned: bool) -> Result<(), UserError> {
+        let current = index::index(settings.index_keys.current(), username);
+        if self.index != current || ids_assigned {
+            self.index = current;
+            self.save(conn, settings)?;
+        }
+        Ok(())
+    }
 
-        if pbkdf2_verify(password.as_bytes(), &password_hash, &salt).is_err() {
-            return Err(());
+    /// Lists the key slots of this User, the password slot first
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    pub fn slots(&self, conn: &Connection) -> Result<Vec<KeySlot>, UserError> {
+        if self.is_decoy() {
+            return Ok(vec![KeySlot { id: None, kind: SlotKind::Password, created_at: None }]);
         }
+        key_slot::list(conn, self.id)
+    }
 
-        let credentials_json = aead_open(&data, password.as_bytes());
+    /// Adds a key slot holding a copy of the vault key wrapped by a new secret, returning the slot
+    /// along with the secret to hand to the User. A new recovery code replaces the previous one.
+    /// The credentials are not resealed
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `kind` - The kind of slot to add, a recovery code or a key file
+    pub fn add_slot(&self, conn: &Connection, settings: &Settings, kind: SlotKind) -> Result<(KeySlot, SecretString), UserError> {
+        let rng = settings.rng.as_ref();
+        let (secret, encoded) = match kind {
+            SlotKind::RecoveryCode => recovery::new_recovery_code(rng),
+            SlotKind::KeyFile => {
+                if !self.is_decoy() && key_slot::count(conn, self.id, kind)? >= MAX_KEY_FILES {
+                    return Err(UserError::InvalidSlot);
+                }
+                recovery::new_key_file(rng)
+            },
+            SlotKind::Password | SlotKind::Shares => return Err(UserError::InvalidSlot)
+        }.map_err(|_| UserError::Internal)?;
+
+        let slot = self.insert_slot(conn, settings, kind, secret.as_bytes())?;
+        Ok((slot, encoded))
+    }
 
-        if credentials_json.is_err() {
-            return Err(());
+    /// Adds a key slot for a new secret split into `count` printable shares, any `threshold` of which
+    /// unlock the vault, returning the slot along with the shares to hand out. A new split replaces
+    /// the previous one. The credentials are not resealed
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `threshold` - The number of shares needed to unlock the vault, at least 2
+    /// * `count` - The number of shares to make, at least `threshold`
+    pub fn add_shares(&self, conn: &Connection, settings: &Settings, threshold: u8, count: u8) -> Result<(KeySlot, Vec<SecretString>), UserError> {
+        // A single share would be a recovery code that can be lost without anyone noticing
+        if threshold < 2 || count < threshold {
+            return Err(UserError::InvalidSlot);
         }
+        let rng = settings.rng.as_ref();
+        let secret = SecretBytes::random(rng, SHARED_SECRET_LEN).map_err(|_| UserError::Internal)?;
+        let shares = shamir::split(rng, secret.as_bytes(), threshold, count).map_err(|_| UserError::Internal)?;
 
-        let credentials_json = credentials_json.unwrap();
-        let credentials_json = str::from_utf8(&credentials_json).unwrap();
-        let credentials: Credentials = serde_json::from_str(credentials_json).unwrap();
+        let slot = self.insert_slot(conn, settings, SlotKind::Shares, secret.as_bytes())?;
+        Ok((slot, shares.iter().map(shamir::encode_share).collect()))
+    }
 
-        Ok(User {
-            id: id,
-            username: username,
-            password: password,
-            credentials: credentials
+    /// Stores a key slot holding a copy of the vault key wrapped by a key derived from a secret,
+    /// returning the slot. A recovery code or split secret replaces the previous one.
+    /// A decoy stores nothing, returning the slot as it would have been stored
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `kind` - The kind of slot
+    /// * `secret` - The bytes of the slot's secret
+    fn insert_slot(&self, conn: &Connection, settings: &Settings, kind: SlotKind, secret: &[u8]) -> Result<KeySlot, UserError> {
+        let slot_key = recovery::slot_key(secret).map_err(|_| UserError::Internal)?;
+        let wrapped_key = aead_seal_with_key(settings.rng.as_ref(), self.vault_key.as_bytes(), slot_key.as_bytes(),
+            self.algorithm, self.key_commitment, &slot_context(self.id, kind))
+            .map_err(|_| UserError::Internal)?;
+
+        let created_at = settings.clock.unix_seconds();
+        if self.is_decoy() {
+            return Ok(KeySlot { id: Some(next_row_id(conn, "key_slots")?), kind, created_at: Some(created_at) });
+        }
+        let id = key_slot::insert(conn, self.id, kind, &wrapped_key, created_at)?;
+        if kind != SlotKind::KeyFile {
+            key_slot::delete_others(conn, self.id, kind, id)?;
+        }
+
+        Ok(KeySlot { id: Some(id), kind, created_at: Some(created_at) })
+    }
+
+    /// Revokes one of this User's key slots. The password slot cannot be revoked
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `id` - The id of the slot
+    pub fn revoke_slot(&self, conn: &Connection, id: i64) -> Result<(), UserError> {
+        // A decoy has no slots of its own
+        if self.is_decoy() {
+            return Err(UserError::NotFound);
+        }
+        key_slot::delete(conn, self.id, id)
+    }
+
+    /// Lists the devices enrolled to unlock this User's vault with a PIN
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    pub fn devices(&self, conn: &Connection) -> Result<Vec<Device>, UserError> {
+        if self.is_decoy() {
+            return Ok(Vec::new());
+        }
+        device::list(conn, self.id)
+    }
+
+    /// Enrolls a device to unlock this User's vault with a short PIN, returning the device along with
+    /// the secret to store on it. The vault key is wrapped by a master key derived from both the secret
+    /// and the PIN, so neither the database nor the device alone can be attacked offline.
+    /// A decoy stores nothing, returning the device as it would have been enrolled
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `name` - The name of the device
+    /// * `pin` - The PIN of the device, at least `MIN_PIN_LEN` characters
+    pub fn enroll_device(&self, conn: &Connection, settings: &Settings, name: &str, pin: &SecretString) -> Result<(Device, SecretString), UserError> {
+        let pin_len = str::from_utf8(pin.as_bytes()).map_err(|_| UserError::InvalidDevice)?.chars().count();
+        if pin_len < MIN_PIN_LEN || (!self.is_decoy() && device::count(conn, self.id)? >= MAX_DEVICES) {
+            return Err(UserError::InvalidDevice);
+        }
+
+        // A device secret is generated and encoded as a key file is
+        let rng = settings.rng.as_ref();
+        let (device_secret, encoded) = recovery::new_key_file(rng).map_err(|_| UserError::Internal)?;
+        let (master_key, password_hash) = new_master_key(rng, &settings.kdf, device_password(&device_secret, pin).as_bytes())
+            .map_err(|_| UserError::Internal)?;
+        let encryption_key = master_key.encryption_key().map_err(|_| UserError::Internal)?;
+        let wrapped_key = aead_seal_with_key(rng, self.vault_key.as_bytes(), encryption_key.as_bytes(), self.algorithm,
+            self.key_commitment, &device_context(self.id))
+            .map_err(|_| UserError::Internal)?;
+
+        let created_at = settings.clock.unix_seconds();
+        let id = if self.is_decoy() {
+            next_row_id(conn, "devices")?
+        } else {
+            device::insert(conn, self.id, name, password_hash.to_phc().as_bytes(), &wrapped_key, created_at)?
+        };
+        let device = Device { id, name: name.to_string(), attempts: 0, created_at: Some(created_at), last_used_at: None };
+        Ok((device, encoded))
+    }
+
+    /// Revokes one of this User's devices, destroying its wrapped key
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `id` - The id of the device
+    pub fn revoke_device(&self, conn: &Connection, id: i64) -> Result<(), UserError> {
+        // A decoy has no devices of its own
+        if self.is_decoy() {
+            return Err(UserError::NotFound);
+        }
+        device::delete(conn, self.id, id)
+    }
+
+    /// Gives this User a keypair for emergency access if it has none, the private key sealed with the vault key
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    fn ensure_keypair(&self, conn: &Connection, settings: &Settings) -> Result<(), UserError> {
+        let has_keypair: bool = conn.query_row("SELECT public_key IS NOT NULL FROM users WHERE id = ?",
+            params![self.id], |row| row.get(0))
+            .map_err(|_| UserError::Internal)?;
+        if has_keypair {
+            return Ok(());
+        }
+
+        let rng = settings.rng.as_ref();
+        let (private_key, public_key) = keypair::new_keypair(rng).map_err(|_| UserError::Internal)?;
+        let private_key = aead_seal_with_key(rng, private_key.as_bytes(), self.vault_key.as_bytes(), self.algorithm,
+            self.key_commitment, &private_key_context(self.id))
+            .map_err(|_| UserError::Internal)?;
+        conn.execute("UPDATE users SET public_key = ?, private_key = ? WHERE id = ? AND public_key IS NULL",
+            params![public_key, private_key, self.id])
+            .map(|_| ())
+            .map_err(|_| UserError::Internal)
+    }
+
+    /// Wraps this User's vault key to the escrow public key if one is configured and the vault key is not
+    /// already wrapped to it. A vault key escrowed under a key no longer configured is kept until rewrapped
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    fn ensure_escrow(&self, conn: &Connection, settings: &Settings) -> Result<(), UserError> {
+        let public_key = match &settings.escrow_public_key {
+            Some(public_key) => public_key,
+            None => return Ok(())
+        };
+        if escrow::get(conn, self.id)?.is_some_and(|escrow| &escrow.public_key == public_key) {
+            return Ok(());
+        }
+
+        let wrapped_key = keypair::wrap_to(settings.rng.as_ref(), public_key, self.vault_key.as_bytes(), self.algorithm,
+            &escrow_context(self.id))
+            .map_err(|_| UserError::Internal)?;
+        let created_at = settings.clock.unix_seconds();
+        escrow::store(conn, self.id, &Escrow { public_key: public_key.clone(), wrapped_key, created_at: Some(created_at) })?;
+        audit::record(conn, self.id, AuditAction::EscrowWrapped, None, Some(&escrow::fingerprint(public_key)), created_at)
+    }
+
+    /// Returns whether the server escrows vault keys, whether this User's is escrowed and under which key,
+    /// along with every recorded wrap and use of this User's escrow. A decoy is shown as escrowed under
+    /// the server's key with nothing recorded
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    pub fn escrow_status(&self, conn: &Connection, settings: &Settings) -> Result<EscrowStatus, UserError> {
+        let server_fingerprint = settings.escrow_public_key.as_deref().map(escrow::fingerprint);
+        if self.is_decoy() {
+            return Ok(EscrowStatus {
+                enabled: server_fingerprint.is_some(),
+                fingerprint: server_fingerprint.clone(),
+                server_fingerprint,
+                escrowed_at: None,
+                events: Vec::new(),
+            });
+        }
+        let escrow = escrow::get(conn, self.id)?;
+        Ok(EscrowStatus {
+            enabled: settings.escrow_public_key.is_some(),
+            server_fingerprint,
+            fingerprint: escrow.as_ref().map(|escrow| escrow::fingerprint(&escrow.public_key)),
+            escrowed_at: escrow.and_then(|escrow| escrow.created_at),
+            events: audit::list(conn, self.id)?,
         })
     }
 
-    /// Saves the state of this User into the database
+    /// Opens the vault of an existing User with the organisation's escrow private key and sets a new
+    /// random password, returning the User and the password. Devices are revoked and the recovery is
+    /// recorded in the User's audit trail
     ///
     /// # Arguments
     ///
     /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
     /// * `username` - The name of the User
-    /// * `password` - The password of the User
-    pub fn save(&self, conn: &Connection) -> Result<(), ()> {
-        let username_hash = hex::encode(hash(self.username.as_bytes()));
+    /// * `private_key` - The escrow private key
+    /// * `officer` - The name of the recovery officer, recorded in the audit trail
+    pub fn recover_with_escrow(conn: &mut Connection, settings: &Settings, username: &str, private_key: &SecretBytes,
+        officer: &str) -> Result<(Self, SecretString), UserError> {
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        let stored_index = find_index(&transaction, settings, username)?;
+        let id: i64 = transaction.query_row("SELECT id FROM users WHERE hash = ? AND index_key IS ?",
+            params![stored_index.hash, stored_index.key_id], |row| row.get(0))
+            .map_err(|_| UserError::Internal)?;
 
-        let mut stmt = conn.prepare("SELECT * FROM users WHERE hash = ? AND id <> ?").unwrap();
-        if stmt.exists(params![username_hash, self.id]).unwrap() {
-            return Err(());
+        let escrow = escrow::get(&transaction, id)?.ok_or(UserError::NotFound)?;
+        let vault_key = keypair::open_from(private_key.as_bytes(), &escrow.wrapped_key, &escrow_context(id))
+            .map_err(|_| UserError::InvalidCredentials)?;
+        let (mut user, _) = User::open_row(&transaction, settings, id, vault_key)?;
+
+        let (_, password) = recovery::new_recovery_code(settings.rng.as_ref()).map_err(|_| UserError::Internal)?;
+        user.index = index::index(settings.index_keys.current(), username);
+        user.save_with_password(&transaction, settings, &password)?;
+        user.revision += 1;
+        device::delete_all(&transaction, id)?;
+        audit::record(&transaction, id, AuditAction::EscrowRecovered, Some(officer),
+            Some(&escrow::fingerprint(&escrow.public_key)), settings.clock.unix_seconds())?;
+
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok((user, password))
+    }
+
+    /// Lists the emergency access grants this User owns or was granted
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    pub fn emergency_grants(&self, conn: &Connection) -> Result<Vec<Grant>, UserError> {
+        if self.is_decoy() {
+            return Ok(Vec::new());
         }
+        emergency::list(conn, self.id)
+    }
 
-        let mut password_hash = [0u8; PASSWORD_HASH_LEN];
-        let salt = pbkdf2_rand_salt(self.password.as_bytes(), &mut password_hash);
+    /// Grants another User emergency access to this User's vault once a request for it
+    /// is approved or left unrejected for the waiting period, returning the grant.
+    /// The vault key is wrapped to the grantee's public key, so no password is shared.
+    /// A decoy stores nothing, returning the grant as it would have been made
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `grantee` - The name of the User to grant access to
+    /// * `access` - What the grantee may do once the grant is approved
+    /// * `wait_days` - How many days this User has to reject a request
+    pub fn grant_emergency_access(&self, conn: &Connection, settings: &Settings, grantee: &str, access: Access,
+        wait_days: u32) -> Result<Grant, UserError> {
+        let grantee_index = find_index(conn, settings, grantee).map_err(|_| UserError::NotFound)?;
+        let (grantee_id, public_key): (i64, Option<Vec<u8>>) = conn.query_row(
+            "SELECT id, public_key FROM users WHERE hash = ? AND index_key IS ?",
+            params![grantee_index.hash, grantee_index.key_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|_| UserError::Internal)?;
+        // Users that have not logged in since keypairs were introduced have no public key yet
+        let public_key = match public_key {
+            Some(public_key) if grantee_id != self.id => public_key,
+            _ => return Err(UserError::InvalidGrant)
+        };
 
-        let credentials_json = serde_json::to_string(&self.credentials).unwrap();
-        let data = aead_seal(&credentials_json.into_bytes(), self.password.as_bytes());
+        let wrapped_key = keypair::wrap_to(settings.rng.as_ref(), &public_key, self.vault_key.as_bytes(), self.algorithm,
+            &grant_context(self.id, grantee_id, access))
+            .map_err(|_| UserError::Internal)?;
+        let wait_seconds = i64::from(wait_days) * SECONDS_PER_DAY;
+        let created_at = settings.clock.unix_seconds();
+        if self.is_decoy() {
+            return Ok(Grant { id: next_row_id(conn, "emergency_grants")?, owner_id: self.id, grantee_id, owned: true, access,
+                wait_seconds, status: Status::Idle, requested_at: None, created_at: Some(created_at) });
+        }
+        let id = emergency::insert(conn, self.id, grantee_id, access, wait_seconds, &wrapped_key, created_at)?;
+        emergency::get(conn, self.id, id)
+    }
 
-        let mut stmt = conn.prepare("UPDATE users SET hash = ?, password = ?, salt = ?, data = ? WHERE id = ?").unwrap();
-        
-        let result = stmt.execute(params![username_hash, password_hash.to_vec(), salt, data.unwrap(), self.id]);
+    /// Requests access through a grant this User was granted, starting its waiting period, returning the grant
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `id` - The id of the grant
+    pub fn request_emergency_access(&self, conn: &Connection, settings: &Settings, id: i64) -> Result<Grant, UserError> {
+        self.change_grant(conn, id, false, &[Status::Idle, Status::Rejected], Status::Requested,
+            Some(settings.clock.unix_seconds()))
+    }
 
-        if result.is_err() {
-            return Err(());       
+    /// Approves a pending request for a grant this User owns before its waiting period is over, returning the grant
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `id` - The id of the grant
+    pub fn approve_emergency_access(&self, conn: &Connection, id: i64) -> Result<Grant, UserError> {
+        self.change_grant(conn, id, true, &[Status::Requested], Status::Approved, None)
+    }
+
+    /// Rejects a pending request for a grant this User owns, returning the grant
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `id` - The id of the grant
+    pub fn reject_emergency_access(&self, conn: &Connection, id: i64) -> Result<Grant, UserError> {
+        self.change_grant(conn, id, true, &[Status::Requested], Status::Rejected, None)
+    }
+
+    /// Revokes a grant this User owns, whatever its status
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `id` - The id of the grant
+    pub fn revoke_emergency_access(&self, conn: &Connection, id: i64) -> Result<(), UserError> {
+        // A decoy has no grants of its own
+        if self.is_decoy() {
+            return Err(UserError::NotFound);
         }
-        Ok(())
+        emergency::delete(conn, self.id, id)
     }
-}
\ No newline at end of file
+
+    /// Moves a grant this User owns or was granted between statuses, returning the grant
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `id` - The id of the grant
+    /// * `owner` - Whether only the owner rather than the grantee may make the change
+    /// * `from` - The statuses the grant may be in
+    /// * `to` - The new status of the grant
+    /// * `requested_at` - When access was requested, if it is being requested
+    fn change_grant(&self, conn: &Connection, id: i64, owner: bool, from: &[Status], to: Status,
+        requested_at: Option<i64>) -> Result<Grant, UserError> {
+        if self.is_decoy() {
+            return Err(UserError::NotFound);
+        }
+        let grant = emergency::get(conn, self.id, id)?;
+        if grant.owned != owner {
+            return Err(UserError::InvalidGrant);
+        }
+        emergency::set_status(conn, id, from, to, requested_at)?;
+        emergency::get(conn, self.id, id)
+    }
+
+    /// Opens the vault of the owner of an approved grant this User was granted, returning the owner
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `id` - The id of the grant
+    pub fn open_emergency_access(&self, conn: &Connection, settings: &Settings, id: i64) -> Result<Self, UserError> {
+        if self.is_decoy() {
+            return Err(UserError::NotFound);
+        }
+        let grant = emergency::get(conn, self.id, id)?;
+        if grant.owned || grant.status != Status::Approved {
+            return Err(UserError::InvalidGrant);
+        }
+
+        let private_key: Vec<u8> = conn.query_row("SELECT private_key FROM users WHERE id = ?", params![self.id], |row| row.get(0))
+            .map_err(|_| UserError::Corrupt)?;
+        let private_key = aead_open_with_key(&private_key, self.vault_key.as_bytes(), &private_key_context(self.id))
+            .map_err(|_| UserError::ContextMismatch)?;
+        let wrapped_key = emergency::wrapped_key(conn, id)?;
+        let vault_key = keypair::open_from(private_key.as_bytes(), &wrapped_key,
+            &grant_context(grant.owner_id, grant.grantee_id, grant.access))
+            .map_err(|_| UserError::ContextMismatch)?;
+
+        User::open_row(conn, settings, grant.owner_id, vault_key).map(|(owner, _)| owner)
+    }
+
+    /// Sets a new password for the owner of an approved takeover grant this User was granted,
+    /// returning the owner. The owner's devices are revoked and the grant is consumed in the same
+    /// transaction, so it cannot be used to take over the vault again
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `id` - The id of the grant
+    /// * `password` - The new password of the owner
+    pub fn take_over(&self, conn: &mut Connection, settings: &Settings, id: i64, password: &SecretString) -> Result<Self, UserError> {
+        if self.is_decoy() {
+            return Err(UserError::NotFound);
+        }
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        if emergency::get(&transaction, self.id, id)?.access != Access::Takeover {
+            return Err(UserError::InvalidGrant);
+        }
+        let mut owner = self.open_emergency_access(&transaction, settings, id)?;
+        emergency::set_status(&transaction, id, &[Status::Approved], Status::Consumed, None)?;
+        owner.save_with_password(&transaction, settings, password)?;
+        owner.revision += 1;
+        device::delete_all(&transaction, owner.id)?;
+
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok(owner)
+    }
+
+    /// Saves the state of this User into the database, sealing the credentials with the vault key
+    /// under the next revision, recording when it was saved
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    pub fn save(&mut self, conn: &Connection, settings: &Settings) -> Result<(), UserError> {
+        if let Some(action) = self.decoy {
+            let data = seal_decoy_data(settings, self.id, &self.vault_key, action, &self.credentials)?;
+            return decoy::store_data(conn, self.id, &data, settings.clock.unix_seconds());
+        }
+
+        let data = wrap(settings, &self.seal(conn, settings)?, &kek_context(self.id, "data"))?;
+
+        let mut stmt = conn.prepare("UPDATE users SET hash = ?, index_key = ?, data = ?, revision = ?, updated_at = ? \
+            WHERE id = ? AND revision IS ?").map_err(|_| UserError::Internal)?;
+
+        let result = stmt.execute(params![self.index.hash, self.index.key_id, data, self.revision + 1,
+            settings.clock.unix_seconds(), self.id, self.revision_column()]);
+
+        match result {
+            Ok(1) => {
+                self.revision += 1;
+                Ok(())
+            },
+            Ok(_) => Err(UserError::StaleRevision),
+            Err(err) => Err(username_taken(err))
+        }
+    }
+
+    /// Saves the state of this User into the database under the next revision along with a
+    /// new password hash and the vault key wrapped by a new master key's encryption subkey.
+    /// Runs the KDF once.
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    /// * `password` - The password to wrap the vault key with
+    fn save_with_password(&self, conn: &Connection, settings: &Settings, password: &SecretString) -> Result<(), UserError> {
+        if let Some(action) = self.decoy {
+            return decoy::store(conn, self.id, &seal_decoy(settings, self.id, password, &self.vault_key, action, &self.credentials)?);
+        }
+
+        let data = wrap(settings, &self.seal(conn, settings)?, &kek_context(self.id, "data"))?;
+
+        let (master_key, password_hash) = new_master_key(settings.rng.as_ref(), &settings.kdf, password.as_bytes())
+            .map_err(|_| UserError::Internal)?;
+        let encryption_key = master_key.encryption_key().map_err(|_| UserError::Internal)?;
+        let wrapped_key = aead_seal_with_key(settings.rng.as_ref(), self.vault_key.as_bytes(), encryption_key.as_bytes(), self.algorithm,
+            self.key_commitment, &key_context(self.id))
+            .map_err(|_| UserError::Internal)?;
+        let wrapped_key = wrap(settings, &wrapped_key, &kek_context(self.id, "vault_key"))?;
+        let password_hash = wrap(settings, password_hash.to_phc().as_bytes(), &kek_context(self.id, "password"))?;
+
+        let mut stmt = conn.prepare("UPDATE users SET hash = ?, index_key = ?, password = ?, salt = NULL, vault_key = ?, \
+            data = ?, revision = ?, updated_at = ? WHERE id = ? AND revision IS ?").map_err(|_| UserError::Internal)?;
+
+        let result = stmt.execute(params![self.index.hash, self.index.key_id, password_hash,
+            wrapped_key, data, self.revision + 1, settings.clock.unix_seconds(), self.id, self.revision_column()]);
+
+        match result {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(UserError::StaleRevision),
+            Err(err) => Err(username_taken(err))
+        }
+    }
+
+    /// Checks that this User's index is not taken by another User and seals the credentials
+    /// under the next revision, returning the sealed data to be saved
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `settings` - The server configuration
+    fn seal(&self, conn: &Connection, settings: &Settings) -> Result<Vec<u8>, UserError> {
+        let mut stmt = conn.prepare("SELECT * FROM users WHERE hash = ? AND index_key IS ? AND id <> ?").map_err(|_| UserError::Internal)?;
+        if stmt.exists(params![self.index.hash, self.index.key_id, self.id]).map_err(|_| UserError::Internal)? {
+            return Err(UserError::InvalidUsername);
+        }
+
+        let mut credentials_json = SecretBytes::with_capacity(CREDENTIALS_JSON_CAPACITY);
+        serde_json::to_writer(&mut credentials_json, &self.credentials).map_err(|_| UserError::Internal)?;
+        let data = aead_seal_with_key(settings.rng.as_ref(), credentials_json.as_bytes(), self.vault_key.as_bytes(), self.algorithm,
+            self.key_commitment, &data_context(self.id, &self.index.hash, self.revision + 1))
+            .map_err(|_| UserError::Internal)?;
+
+        Ok(data)
+    }
+
+    /// Rewraps every stored blob of every User with the current key-encryption key,
+    /// without needing any password. Returns the number of rows rewrapped, and the number
+    /// skipped because they were saved by another request while being rewrapped
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A rusqlite connection to the database
+    /// * `rng` - The source of the nonces
+    /// * `keks` - The key-encryption keys, holding every key rows may be wrapped with
+    pub fn rewrap_all(conn: &mut Connection, rng: &dyn Rng, keks: &KeyRing) -> Result<(usize, usize), UserError> {
+        let transaction = conn.transaction().map_err(|_| UserError::Internal)?;
+        let mut select = transaction.prepare("SELECT id, password, vault_key, data, decoy_password, decoy_key, decoy_data, \
+            revision FROM users").map_err(|_| UserError::Internal)?;
+        let mut update = transaction.prepare("UPDATE users SET password = ?, vault_key = ?, data = ?, decoy_password = ?, \
+            decoy_key = ?, decoy_data = ? WHERE id = ? AND revision IS ?").map_err(|_| UserError::Internal)?;
+        let mut rows = select.query(params![]).map_err(|_| UserError::Internal)?;
+
+        let (mut rewrapped, mut skipped) = (0, 0);
+        while let Some(row) = rows.next().map_err(|_| UserError::Internal)? {
+            let id: i64 = row.get(0).map_err(|_| UserError::Corrupt)?;
+            let revision: Option<i64> = row.get(7).map_err(|_| UserError::Corrupt)?;
+            let mut columns = Vec::new();
+            for (index, column) in [(1, "password"), (2, "vault_key"), (3, "data"), (4, "decoy_password"), (5, "decoy_key"),
+                (6, "decoy_data")].iter() {
+                let blob: Option<Vec<u8>> = row.get(*index).map_err(|_| UserError::Corrupt)?;
+                let context = kek_context(id, column);
+                columns.push(match blob {
+                    Some(blob) => {
+                        // Rewrapping every row is also how rows stored before key-encryption keys are migrated
+                        let (blob, _) = kek_unwrap(&blob, Some(keks), true, &context).map_err(|_| UserError::Internal)?;
+                        Some(kek_wrap(rng, &blob, keks, &context).map_err(|_| UserError::Internal)?)
+                    },
+                    None => None
+                });
+            }
+
+            match update.execute(params![columns[0], columns[1], columns[2], columns[3], columns[4], columns[5], id, revision]) {
+                Ok(1) => rewrapped += 1,
+                Ok(_) => skipped += 1,
+                Err(_) => return Err(UserError::Internal)
+            }
+        }
+        drop(rows);
+        drop(select);
+        drop(update);
+
+        transaction.commit().map_err(|_| UserError::Internal)?;
+        Ok((rewrapped, skipped))
+    }
+
+    /// Whether this User holds a decoy, which answers as an account with nothing but its credentials would.
+    /// Its vault key is never bound to anything belonging to the real vault, and nothing of the real vault is listed
+    fn is_decoy(&self) -> bool {
+        self.decoy.is_some()
+    }
+
+    /// The value of the `revision` column this User was fetched with,
+    /// NULL for rows saved before revisions were recorded
+    fn revision_column(&self) -> Option<i64> {
+        if self.revision == 0 {
+            None
+        } else {
+            Some(self.revision)
+        }
+    }
+}
+
+/// Checks that a username is well formed and that no other User is indexed under it,
+/// under any index key since Users indexed under older keys have not been reindexed yet
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `settings` - The server configuration
+/// * `username` - The username to check
+/// * `id` - The id of the User's own row when renaming, which may hold the username
+fn check_username_free(conn: &Connection, settings: &Settings, username: &str, id: Option<i64>) -> Result<(), UserError> {
+    if username.contains(':') {
+        return Err(UserError::InvalidUsername);
+    }
+
+    let mut stmt = conn.prepare("SELECT * FROM users WHERE hash = ? AND index_key IS ? AND id IS NOT ?")
+        .map_err(|_| UserError::Internal)?;
+    for index in index::candidates(&settings.index_keys, username) {
+        if stmt.exists(params![index.hash, index.key_id, id]).map_err(|_| UserError::Internal)? {
+            return Err(UserError::InvalidUsername);
+        }
+    }
+    Ok(())
+}
+
+/// Turns a failed write of a User's row into the error to return, the username being taken
+/// if the write broke the unique index on the `hash` column
+///
+/// # Arguments
+///
+/// * `err` - The error of the write
+fn username_taken(err: rusqlite::Error) -> UserError {
+    match err {
+        rusqlite::Error::SqliteFailure(err, _) if err.code == ErrorCode::ConstraintViolation => UserError::InvalidUsername,
+        _ => UserError::Internal
+    }
+}
+
+/// Finds the index a username is stored under, which may be the index of an older key
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `settings` - The server configuration
+/// * `username` - The name of the User
+fn find_index(conn: &Connection, settings: &Settings, username: &str) -> Result<UsernameIndex, UserError> {
+    let mut stmt = conn.prepare("SELECT * FROM users WHERE hash = ? AND index_key IS ?").map_err(|_| UserError::Internal)?;
+    for index in index::candidates(&settings.index_keys, username) {
+        if stmt.exists(params![index.hash, index.key_id]).map_err(|_| UserError::Internal)? {
+            return Ok(index);
+        }
+    }
+    Err(UserError::InvalidCredentials)
+}
+
+/// Builds the context a User's sealed data is bound to
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+/// * `username_hash` - The index the User is looked up by
+/// * `revision` - The revision the data is saved under
+fn data_context(id: i64, username_hash: &str, revision: i64) -> Vec<u8> {
+    let mut context = b"jpassword data".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context.extend(&revision.to_be_bytes());
+    context.extend(username_hash.as_bytes());
+    context
+}
+
+/// Builds the context a User's wrapped vault key is bound to
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+fn key_context(id: i64) -> Vec<u8> {
+    let mut context = b"jpassword vault key".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context
+}
+
+/// Builds the context a User's vault key is bound to when wrapped by a key slot
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+/// * `kind` - The kind of the slot
+fn slot_context(id: i64, kind: SlotKind) -> Vec<u8> {
+    let mut context = b"jpassword key slot".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context.extend(kind.name().as_bytes());
+    context
+}
+
+/// Seals a User's decoy under a new duress password, returning its blobs. Runs the KDF once
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `id` - The id of the User's row
+/// * `password` - The duress password
+/// * `vault_key` - The decoy's vault key
+/// * `action` - What happens to the real vault when the duress password is used
+/// * `credentials` - The decoy's credentials
+fn seal_decoy(settings: &Settings, id: i64, password: &SecretString, vault_key: &SecretBytes, action: DuressAction,
+    credentials: &Credentials) -> Result<DecoyColumns, UserError> {
+    let (master_key, password_hash) = new_master_key(settings.rng.as_ref(), &settings.kdf, password.as_bytes())
+        .map_err(|_| UserError::Internal)?;
+    let encryption_key = master_key.encryption_key().map_err(|_| UserError::Internal)?;
+    seal_decoy_columns(settings, id, &password_hash, &encryption_key, vault_key, action, credentials)
+}
+
+/// Seals filler in place of a decoy, returning blobs that cannot be told apart from a real decoy's
+/// while no password opens them
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `id` - The id of the User's row
+fn seal_filler_decoy(settings: &Settings, id: i64) -> Result<DecoyColumns, UserError> {
+    let rng = settings.rng.as_ref();
+    let (encryption_key, password_hash) = unmatched_password_hash(rng, &settings.kdf).map_err(|_| UserError::Internal)?;
+    let vault_key = SecretBytes::random(rng, VAULT_KEY_LEN).map_err(|_| UserError::Internal)?;
+    seal_decoy_columns(settings, id, &password_hash, &encryption_key, &vault_key, DuressAction::None, &Credentials::new())
+}
+
+/// Seals every blob of a decoy, returning them
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `id` - The id of the User's row
+/// * `password_hash` - The hash of the duress password
+/// * `encryption_key` - The encryption subkey of the duress password's master key
+/// * `vault_key` - The decoy's vault key
+/// * `action` - What happens to the real vault when the duress password is used
+/// * `credentials` - The decoy's credentials
+fn seal_decoy_columns(settings: &Settings, id: i64, password_hash: &PasswordHash, encryption_key: &SecretBytes,
+    vault_key: &SecretBytes, action: DuressAction, credentials: &Credentials) -> Result<DecoyColumns, UserError> {
+    let wrapped_key = aead_seal_with_key(settings.rng.as_ref(), vault_key.as_bytes(), encryption_key.as_bytes(),
+        settings.aead_algorithm, settings.key_commitment, &decoy_key_context(id))
+        .map_err(|_| UserError::Internal)?;
+    Ok(DecoyColumns {
+        password: wrap(settings, password_hash.to_phc().as_bytes(), &kek_context(id, "decoy_password"))?,
+        vault_key: wrap(settings, &wrapped_key, &kek_context(id, "decoy_key"))?,
+        data: seal_decoy_data(settings, id, vault_key, action, credentials)?,
+    })
+}
+
+/// Seals the credentials and duress action of a decoy, returning the blob to store.
+/// Decoys are sealed with the configured algorithm whatever the real vault uses, as filler is
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `id` - The id of the User's row
+/// * `vault_key` - The decoy's vault key
+/// * `action` - What happens to the real vault when the duress password is used
+/// * `credentials` - The decoy's credentials
+fn seal_decoy_data(settings: &Settings, id: i64, vault_key: &SecretBytes, action: DuressAction,
+    credentials: &Credentials) -> Result<Vec<u8>, UserError> {
+    let decoy_json = decoy::encode(action, credentials)?;
+    let data = aead_seal_with_key(settings.rng.as_ref(), decoy_json.as_bytes(), vault_key.as_bytes(),
+        settings.aead_algorithm, settings.key_commitment, &decoy_data_context(id))
+        .map_err(|_| UserError::Internal)?;
+    wrap(settings, &data, &kek_context(id, "decoy_data"))
+}
+
+/// Locks a User's real vault by replacing its password hash and wrapped vault key with filler
+/// and revoking its devices, so nothing but a key slot opens it until the password is reset. Runs no KDF
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `settings` - The server configuration
+/// * `id` - The id of the User's row
+/// * `data` - The real vault as stored, whose envelope the filler key matches
+fn lock_vault(conn: &Connection, settings: &Settings, id: i64, data: &[u8]) -> Result<(), UserError> {
+    let rng = settings.rng.as_ref();
+    let data_header = envelope::parse(data).map_err(|_| UserError::Corrupt)?.header;
+    let (encryption_key, password_hash) = unmatched_password_hash(rng, &settings.kdf).map_err(|_| UserError::Internal)?;
+    let vault_key = SecretBytes::random(rng, VAULT_KEY_LEN).map_err(|_| UserError::Internal)?;
+    let wrapped_key = aead_seal_with_key(rng, vault_key.as_bytes(), encryption_key.as_bytes(), data_header.algorithm,
+        data_header.is_committed(), &key_context(id))
+        .map_err(|_| UserError::Internal)?;
+
+    let password_hash = wrap(settings, password_hash.to_phc().as_bytes(), &kek_context(id, "password"))?;
+    let wrapped_key = wrap(settings, &wrapped_key, &kek_context(id, "vault_key"))?;
+    conn.execute("UPDATE users SET password = ?, salt = NULL, vault_key = ? WHERE id = ?",
+        params![password_hash, wrapped_key, id])
+        .map_err(|_| UserError::Internal)?;
+    device::delete_all(conn, id)
+}
+
+/// Joins a device secret and PIN into the password a device's master key is derived from.
+/// The secret has a fixed length, so the two cannot run into each other
+///
+/// # Arguments
+///
+/// * `device_secret` - The bytes of the device secret
+/// * `pin` - The PIN of the device
+fn device_password(device_secret: &SecretBytes, pin: &SecretString) -> SecretBytes {
+    let mut password = SecretBytes::with_capacity(device_secret.as_bytes().len() + pin.as_bytes().len());
+    password.as_mut_vec().extend_from_slice(device_secret.as_bytes());
+    password.as_mut_vec().extend_from_slice(pin.as_bytes());
+    password
+}
+
+/// Builds the context a User's vault key is bound to when wrapped for one of its devices
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+fn device_context(id: i64) -> Vec<u8> {
+    let mut context = b"jpassword device".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context
+}
+
+/// Builds the context a User's decoy vault key is bound to when wrapped by the duress password
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+fn decoy_key_context(id: i64) -> Vec<u8> {
+    let mut context = b"jpassword decoy key".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context
+}
+
+/// Builds the context a User's decoy is bound to when sealed with the decoy vault key
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+fn decoy_data_context(id: i64) -> Vec<u8> {
+    let mut context = b"jpassword decoy data".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context
+}
+
+/// Builds the context a User's private key is bound to when sealed with the vault key
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+fn private_key_context(id: i64) -> Vec<u8> {
+    let mut context = b"jpassword private key".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context
+}
+
+/// Builds the context a User's vault key is bound to when wrapped to the escrow public key
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+fn escrow_context(id: i64) -> Vec<u8> {
+    let mut context = b"jpassword escrow".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context
+}
+
+/// Builds the context an owner's vault key is bound to when wrapped to a grantee
+///
+/// # Arguments
+///
+/// * `owner_id` - The id of the owner's row
+/// * `grantee_id` - The id of the grantee's row
+/// * `access` - What the grantee may do once the grant is approved
+fn grant_context(owner_id: i64, grantee_id: i64, access: Access) -> Vec<u8> {
+    let mut context = b"jpassword emergency grant".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&owner_id.to_be_bytes());
+    context.extend(&grantee_id.to_be_bytes());
+    context.extend(access.name().as_bytes());
+    context
+}
+
+/// Builds the context a User's blob is bound to when wrapped with a key-encryption key
+///
+/// # Arguments
+///
+/// * `id` - The id of the User's row
+/// * `column` - The column holding the blob
+fn kek_context(id: i64, column: &str) -> Vec<u8> {
+    let mut context = b"jpassword kek".to_vec();
+    context.push(CONTEXT_VERSION);
+    context.extend(&id.to_be_bytes());
+    context.extend(column.as_bytes());
+    context
+}
+
+/// Wraps a blob to be stored with the current key-encryption key if one is configured
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `blob` - The blob to wrap
+/// * `context` - The context to bind the wrapped blob to
+fn wrap(settings: &Settings, blob: &[u8], context: &[u8]) -> Result<Vec<u8>, UserError> {
+    match &settings.keks {
+        Some(keks) => kek_wrap(settings.rng.as_ref(), blob, keks, context).map_err(|_| UserError::Internal),
+        None => Ok(blob.to_vec())
+    }
+}
+
+/// The id the next row inserted into a table would be given, for a decoy to answer as if it inserted one
+///
+/// # Arguments
+///
+/// * `conn` - A rusqlite connection to the database
+/// * `table` - The name of the table
+fn next_row_id(conn: &Connection, table: &str) -> Result<i64, UserError> {
+    conn.query_row(&format!("SELECT COALESCE(MAX(id), 0) + 1 FROM {}", table), params![], |row| row.get(0))
+        .map_err(|_| UserError::Internal)
+}
+
+/// Wraps the blobs of a decoy stored before key-encryption keys were configured, if migrating to them.
+/// Returns whether any blob was wrapped and so needs saving
+///
+/// # Arguments
+///
+/// * `settings` - The server configuration
+/// * `id` - The id of the User's row
+/// * `columns` - The blobs of the decoy as stored
+fn wrap_decoy_columns(settings: &Settings, id: i64, columns: &mut DecoyColumns) -> Result<bool, UserError> {
+    let keks = match &settings.keks {
+        Some(keks) if settings.kek_migrate => keks,
+        _ => return Ok(false)
+    };
+    let mut wrapped = false;
+    for (blob, column) in [(&mut columns.password, "decoy_password"), (&mut columns.vault_key, "decoy_key"),
+        (&mut columns.data, "decoy_data")].iter_mut() {
+        let context = kek_context(id, column);
+        let (unwrapped, kek_id) = kek_unwrap(blob, Some(keks), true, &context).map_err(|_| UserError::Internal)?;
+        if kek_id.is_none() {
+            **blob = kek_wrap(settings.rng.as_ref(), &unwrapped, keks, &context).map_err(|_| UserError::Internal)?;
+            wrapped = true;
+        }
+    }
+    Ok(wrapped)
+}
+
+/// Reads the stored password hash of a User. Rows written before Argon2id
+/// hold a raw PBKDF2 hash with a separate salt, newer rows hold a PHC string.
+///
+/// # Arguments
+///
+/// * `password` - The `password` column of the User
+/// * `salt` - The `salt` column of the User, only set on legacy rows
+fn parse_password_hash(password: Vec<u8>, salt: Option<Vec<u8>>) -> Result<PasswordHash, ()> {
+    match salt {
+        Some(salt) => Ok(PasswordHash {
+            kdf: Kdf::legacy(),
+            salt,
+            hash: password,
+            split: false
+        }),
+        None => PasswordHash::from_phc(str::from_utf8(&password).map_err(|_| ())?).map_err(|_| ())
+    }
+}
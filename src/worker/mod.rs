@@ -0,0 +1,80 @@
+use futures::channel::oneshot;
+use std::{panic::{self, AssertUnwindSafe}, sync::{Arc, Mutex, mpsc::{self, Receiver, SyncSender, TrySendError}}, thread};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads running blocking work, such as KDF runs and SQLite queries,
+/// off of the actix event loop with a bounded queue of pending work
+#[derive(Clone)]
+pub struct WorkerPool {
+    sender: SyncSender<Job>,
+}
+
+/// Represents the reasons work could not be run on the WorkerPool
+#[derive(Debug, PartialEq)]
+pub enum WorkerError {
+    /// Every thread is busy and the queue is full
+    Overloaded,
+    /// The work panicked before returning a result
+    Panicked,
+}
+
+impl WorkerPool {
+    /// Starts the threads of a new WorkerPool, returning the pool
+    ///
+    /// # Arguments
+    ///
+    /// * `threads` - The number of threads running work concurrently
+    /// * `queue_len` - The number of jobs that may wait for a thread before work is rejected
+    pub fn new(threads: usize, queue_len: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("jpassword-worker-{}", i))
+                .spawn(move || work(receiver))
+                .unwrap();
+        }
+
+        WorkerPool { sender }
+    }
+
+    /// Queues work to run on the pool, returning its result once run
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The blocking work to run
+    pub async fn run<F, T>(&self, f: F) -> Result<T, WorkerError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = result_sender.send(f());
+        });
+
+        match self.sender.try_send(job) {
+            Ok(()) => result_receiver.await.map_err(|_| WorkerError::Panicked),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => Err(WorkerError::Overloaded),
+        }
+    }
+}
+
+/// Runs jobs from the queue until the pool is dropped
+///
+/// # Arguments
+///
+/// * `receiver` - The queue shared by every thread of the pool
+fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            // A panicking job drops its result sender, which is reported to the caller
+            Ok(job) => { let _ = panic::catch_unwind(AssertUnwindSafe(job)); },
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use jpassword::{clock::SystemClock, config::settings::Settings, crypto::{aead::AeadAlgorithm, hash::{Argon2Params, Kdf},
+    keyring::KeyRing, rand::SystemRng}};
+use std::{fs, path::{Path, PathBuf}, sync::Arc};
+
+/// Cheap Argon2id parameters, as the tests do not depend on the cost of a hash
+pub const KDF: Kdf = Kdf::Argon2id(Argon2Params { memory_kib: 64, iterations: 1, parallelism: 1 });
+
+/// Returns an empty directory for a test, unique to the test and the process
+///
+/// # Arguments
+///
+/// * `name` - The name of the test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("jpassword-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Returns a server configuration with every setting pinned rather than read from the environment,
+/// indexing usernames under a fixed key written to `dir`
+///
+/// # Arguments
+///
+/// * `dir` - The directory the index key file is written to
+pub fn settings(dir: &Path) -> Settings {
+    let index_key_file = dir.join("index.key");
+    fs::write(&index_key_file, format!("1:{}\n", hex::encode([0x20u8; 32]))).unwrap();
+    let index_keys = KeyRing::load(&index_key_file).unwrap();
+    Settings {
+        kdf: KDF,
+        kdf_target: None,
+        aead_algorithm: AeadAlgorithm::Aes256Gcm,
+        key_commitment: false,
+        worker_threads: 1,
+        worker_queue_len: 64,
+        duress: false,
+        lock_memory: false,
+        disable_core_dumps: false,
+        index_key_file,
+        index_keys: Arc::new(index_keys),
+        kek_file: None,
+        keks: None,
+        kek_migrate: false,
+        escrow_public_key: None,
+        rng: Arc::new(SystemRng::new()),
+        clock: Arc::new(SystemClock),
+    }
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::KDF;
+use jpassword::{clock::FixedClock, crypto::{aead::{aead_seal_with_key, seal_with_header, AeadAlgorithm},
+    envelope::Header, hash::{hash, new_master_key}, rand::{generate_rand_vec, SeededRng}, secret::SecretString},
+    db::create_db_then_pool, models::user::User};
+use rusqlite::params;
+use std::{fs, sync::Arc, time::{Duration, UNIX_EPOCH}};
+
+const SEED: &[u8] = b"jpassword golden";
+const KEY: [u8; 32] = [0x40; 32];
+
+const SEALED_WITH_KEY: &str = concat!(
+    "4a505745030100000c85e40d406e17ebbbc5f4769020c129996a837c2bf089b9a9769a95af79e0b7e3f82b51faf3c60a",
+    "ef4807f8cf68579f9124dde3784d188f2f2b0660bd1c329c74cda5e5fc78ee");
+const SEALED_WITH_HEADER: &str = concat!(
+    "4a505745030202000000400000000100000001103c24738d9058e9beef377673703b5aa00cbc05571ace76bc90e629ab",
+    "1200fc43d048ceb64fe0edc9ac173c8e34ac7b0211e9cb7d5e403f");
+const PHC: &str = "$argon2id$v=19$m=64,t=1,p=1,split=1$heQNQG4X67vF9HaQPCRzjZBY6b7vN3ZzcDtaoLwFVxo$qVsDx/ZnIaWVZ8A3/HjxCLTnRZcUsTRzF5hy8TChcds";
+const USER_DATA_SHA256: &str = "7e3e66a3131c40bb89e24e7a0adf8cbdafe27d37cc2010f5550bd8ebadb4b2cc";
+
+#[test]
+fn sealed_output_is_reproducible() {
+    let rng = SeededRng::new(SEED);
+    let sealed = aead_seal_with_key(&rng, b"plaintext", &KEY, AeadAlgorithm::Aes256Gcm, true, b"context").unwrap();
+    assert_eq!(hex::encode(sealed), SEALED_WITH_KEY);
+
+    let algorithm = AeadAlgorithm::ChaCha20Poly1305;
+    let salt = generate_rand_vec(&rng, 16).unwrap();
+    let nonce = generate_rand_vec(&rng, algorithm.nonce_len()).unwrap();
+    let header = Header::new(algorithm, Some(KDF), salt, nonce);
+    let mut key = [0u8; 32];
+    KDF.derive(b"password", &header.salt, &mut key).unwrap();
+    assert_eq!(hex::encode(seal_with_header(&header, &key, b"plaintext", b"context").unwrap()), SEALED_WITH_HEADER);
+}
+
+#[test]
+fn password_hash_is_reproducible() {
+    let (_, password_hash) = new_master_key(&SeededRng::new(SEED), &KDF, b"password").unwrap();
+    assert_eq!(password_hash.to_phc(), PHC);
+}
+
+#[test]
+fn stored_user_is_reproducible() {
+    let dir = common::temp_dir("golden");
+    let pool = create_db_then_pool(&dir.join("golden.db"), 1);
+    let mut conn = pool.get().unwrap();
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    // Every setting is pinned, so the output does not depend on the environment the tests run in
+    let mut settings = common::settings(&dir);
+    settings.rng = Arc::new(SeededRng::new(SEED));
+    settings.clock = Arc::new(FixedClock::new(now));
+
+    User::create(&mut conn, &settings, "golden".to_string(), SecretString::from("password".to_string())).unwrap();
+    let (data, updated_at): (Vec<u8>, i64) = conn.query_row("SELECT data, updated_at FROM users WHERE id = 1", params![],
+        |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    drop(conn);
+    drop(pool);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(hex::encode(hash(&data)), USER_DATA_SHA256);
+    assert_eq!(updated_at, 1_700_000_000);
+}
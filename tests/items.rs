@@ -0,0 +1,108 @@
+use jpassword::{crypto::secret::SecretString, models::{credentials::{CredentialError, Credentials, DecodeError},
+    item::{ApiKey, Card, Identity, Item, Wifi, WifiSecurity}}};
+
+fn secret(value: &str) -> SecretString {
+    SecretString::from(value.to_string())
+}
+
+/// Returns a card expiring in the given month and year
+fn card(number: &str, expiry_month: u8, expiry_year: u16, cvv: &str) -> Item {
+    Item::Card(Card { cardholder: "A Person".to_string(), number: secret(number), expiry_month, expiry_year,
+        cvv: secret(cvv) })
+}
+
+fn wifi(ssid: &str, security: WifiSecurity, password: &str) -> Item {
+    Item::Wifi(Wifi { ssid: ssid.to_string(), security, password: secret(password) })
+}
+
+fn identity(email: &str) -> Item {
+    Item::Identity(Identity { first_name: String::new(), last_name: String::new(), email: email.to_string(),
+        phone: String::new(), address: String::new(), document_number: SecretString::default() })
+}
+
+#[test]
+fn card_number_must_pass_luhn_check() {
+    assert_eq!(card("4111 1111 1111 1111", 12, 2030, "123").validate(), Ok(()));
+    assert_eq!(card("378282246310005", 1, 2030, "1234").validate(), Ok(()));
+    assert_eq!(card("4111-1111-1111-1112", 12, 2030, "").validate(), Err(CredentialError::InvalidItem));
+    assert_eq!(card("378282246310006", 1, 2030, "").validate(), Err(CredentialError::InvalidItem));
+}
+
+#[test]
+fn card_fields_are_checked() {
+    let invalid = Err(CredentialError::InvalidItem);
+    // Passes the Luhn check but is too short for a card
+    assert_eq!(card("79927398713", 1, 2030, "").validate(), invalid);
+    assert_eq!(card("411111111111111a", 1, 2030, "").validate(), invalid);
+    assert_eq!(card("4111111111111111", 0, 2030, "").validate(), invalid);
+    assert_eq!(card("4111111111111111", 13, 2030, "").validate(), invalid);
+    assert_eq!(card("4111111111111111", 1, 1999, "").validate(), invalid);
+    assert_eq!(card("4111111111111111", 1, 2100, "").validate(), invalid);
+    assert_eq!(card("4111111111111111", 1, 30, "").validate(), invalid);
+    assert_eq!(card("4111111111111111", 1, 2030, "12").validate(), invalid);
+    assert_eq!(card("4111111111111111", 1, 2030, "12a").validate(), invalid);
+}
+
+#[test]
+fn other_item_fields_are_checked() {
+    let invalid = Err(CredentialError::InvalidItem);
+    assert_eq!(identity("").validate(), Ok(()));
+    assert_eq!(identity("someone@example.com").validate(), Ok(()));
+    assert_eq!(identity("someone").validate(), invalid);
+    assert_eq!(identity("@example.com").validate(), invalid);
+    assert_eq!(identity("some@one@example.com").validate(), invalid);
+
+    assert_eq!(Item::ApiKey(ApiKey { key: secret("key"), secret: SecretString::default(), url: String::new() })
+        .validate(), Ok(()));
+    assert_eq!(Item::ApiKey(ApiKey { key: SecretString::default(), secret: secret("secret"), url: String::new() })
+        .validate(), invalid);
+
+    assert_eq!(wifi("home", WifiSecurity::Open, "").validate(), Ok(()));
+    assert_eq!(wifi("home", WifiSecurity::Wpa, "passphrase").validate(), Ok(()));
+    assert_eq!(wifi("home", WifiSecurity::Wep, "abcde").validate(), Ok(()));
+    assert_eq!(wifi("home", WifiSecurity::Wep, "0123456789").validate(), Ok(()));
+    assert_eq!(wifi("", WifiSecurity::Open, "").validate(), invalid);
+    assert_eq!(wifi(&"n".repeat(33), WifiSecurity::Open, "").validate(), invalid);
+    assert_eq!(wifi("home", WifiSecurity::Open, "password").validate(), invalid);
+    assert_eq!(wifi("home", WifiSecurity::Wpa, "short").validate(), invalid);
+    assert_eq!(wifi("home", WifiSecurity::Wep, "abcdef").validate(), invalid);
+    assert_eq!(wifi("home", WifiSecurity::Wep, "012345678g").validate(), invalid);
+}
+
+#[test]
+fn untyped_credential_loads_as_login() {
+    let legacy = br#"{"credentials":[{"name":"Mail","url":"https://mail.example.com","username":"someone",
+        "password":"hunter2"}]}"#;
+    let credentials = Credentials::decode(legacy).unwrap();
+    let credential = &credentials.credentials[0];
+    assert_eq!(credential.id, "");
+    assert_eq!(credential.name, "Mail");
+    match &credential.item {
+        Item::Login(login) => {
+            assert_eq!(login.url, "https://mail.example.com");
+            assert_eq!(login.username.as_bytes(), b"someone");
+            assert_eq!(login.password.as_bytes(), b"hunter2");
+        },
+        _ => panic!("an untyped credential must load as a login"),
+    }
+
+    // Saving gives the login its type, which loads back the same
+    let saved = serde_json::to_string(&credentials).unwrap();
+    assert_eq!(saved, r#"{"credentials":[{"id":"","name":"Mail","type":"login","url":"https://mail.example.com","#
+        .to_string() + r#""username":"someone","password":"hunter2"}]}"#);
+    let reloaded = Credentials::decode(saved.as_bytes()).unwrap();
+    assert_eq!(serde_json::to_string(&reloaded).unwrap(), saved);
+}
+
+#[test]
+fn typed_credentials_need_their_fields() {
+    let decode = |json: &str| Credentials::decode(json.as_bytes()).err();
+    let card = r#"{"credentials":[{"id":"1","name":"Card","type":"card","number":"4111 1111 1111 1111",
+        "expiry_month":1,"expiry_year":2030}]}"#;
+    assert_eq!(decode(card), None);
+    // A typed item missing its fields is not read as a login
+    assert_eq!(decode(r#"{"credentials":[{"name":"n","type":"note","url":"u","username":"x","password":"y"}]}"#),
+        Some(DecodeError::Unexpected));
+    assert_eq!(decode(r#"{"credentials":[{"name":"n","type":"unknown"}]}"#), Some(DecodeError::Unexpected));
+    assert_eq!(decode(r#"{"credentials":[{"name":"n","url":"u"}]}"#), Some(DecodeError::Unexpected));
+}
@@ -0,0 +1,47 @@
+use jpassword::crypto::{aead::{aead_open, seal_with_header, AeadAlgorithm}, envelope::{self, EnvelopeError, Header},
+    hash::{Argon2Params, Kdf, PasswordHash, PhcError, MAX_ITERATIONS, MAX_MEMORY_KIB}};
+
+#[test]
+fn phc_params_beyond_limits_are_refused() {
+    let salt = "c2FsdHNhbHRzYWx0c2FsdA";
+    let hash = "aGFzaGhhc2hoYXNoaGFzaA";
+    assert!(PasswordHash::from_phc(&format!("$argon2id$v=19$m=19456,t=2,p=1${}${}", salt, hash)).is_ok());
+    assert_eq!(PasswordHash::from_phc(&format!("$argon2id$v=19$m=4294967295,t=2,p=1${}${}", salt, hash)),
+        Err(PhcError::ParamsOutOfBounds));
+    assert_eq!(PasswordHash::from_phc(&format!("$argon2id$v=19$m=19456,t=4294967295,p=1${}${}", salt, hash)),
+        Err(PhcError::ParamsOutOfBounds));
+    assert_eq!(PasswordHash::from_phc(&format!("$argon2id$v=19$m=19456,t=2,p=255${}${}", salt, hash)),
+        Err(PhcError::ParamsOutOfBounds));
+    assert_eq!(PasswordHash::from_phc(&format!("$pbkdf2-sha256$i=4294967295${}${}", salt, hash)),
+        Err(PhcError::ParamsOutOfBounds));
+    assert_eq!(PasswordHash::from_phc("$argon2id$v=19$m=x$$"), Err(PhcError::Malformed));
+}
+
+#[test]
+fn envelope_params_beyond_limits_are_refused() {
+    let kdf = Kdf::Argon2id(Argon2Params { memory_kib: 64, iterations: 1, parallelism: 1 });
+    let algorithm = AeadAlgorithm::Aes256Gcm;
+    let header = Header::new(algorithm, Some(kdf), vec![1; 16], vec![2; algorithm.nonce_len()]);
+    let mut key = [0u8; 32];
+    kdf.derive(b"password", &header.salt, &mut key).unwrap();
+    let sealed = seal_with_header(&header, &key, b"plaintext", b"context").unwrap();
+    assert_eq!(aead_open(&sealed, b"password", &Kdf::legacy(), b"context").unwrap().as_bytes(), b"plaintext");
+
+    // The memory cost is the first parameter after the magic, version, algorithm and KDF ids
+    let mut oversized = sealed.clone();
+    oversized[7..11].copy_from_slice(&(MAX_MEMORY_KIB + 1).to_be_bytes());
+    assert_eq!(envelope::parse(&oversized).err(), Some(EnvelopeError::KdfParamsOutOfBounds));
+    assert!(aead_open(&oversized, b"password", &Kdf::legacy(), b"context").is_err());
+
+    let mut oversized = sealed;
+    oversized[11..15].copy_from_slice(&(MAX_ITERATIONS + 1).to_be_bytes());
+    assert_eq!(envelope::parse(&oversized).err(), Some(EnvelopeError::KdfParamsOutOfBounds));
+}
+
+#[test]
+fn derive_refuses_params_beyond_limits() {
+    let mut out = [0u8; 32];
+    let kdf = Kdf::Argon2id(Argon2Params { memory_kib: u32::MAX, iterations: 1, parallelism: 1 });
+    assert!(kdf.derive(b"password", &[0u8; 16], &mut out).is_err());
+    assert!(Kdf::Pbkdf2Sha256 { iterations: u32::MAX }.derive(b"password", &[0u8; 16], &mut out).is_err());
+}
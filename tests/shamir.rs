@@ -0,0 +1,87 @@
+use jpassword::crypto::{rand::SeededRng, secret::SecretBytes, shamir::{combine, decode_share, encode_share, split, Share,
+    SHARED_SECRET_LEN}};
+
+const SECRET: [u8; SHARED_SECRET_LEN] = *b"sixteen byte key";
+
+/// Returns a copy of a share, as shares are not cloned outside tests
+fn copy(share: &Share) -> Share {
+    let mut y = SecretBytes::zeroed(share.y.as_bytes().len());
+    y.as_mut_bytes().copy_from_slice(share.y.as_bytes());
+    Share { threshold: share.threshold, x: share.x, split_id: share.split_id, y }
+}
+
+/// Returns the shares whose bit is set in `mask`
+fn subset(shares: &[Share], mask: u32) -> Vec<Share> {
+    shares.iter().enumerate().filter(|(i, _)| mask & (1 << i) != 0).map(|(_, share)| copy(share)).collect()
+}
+
+#[test]
+fn enough_shares_rebuild_secret() {
+    let rng = SeededRng::new(b"jpassword shamir");
+    for count in 1..=5u8 {
+        for threshold in 1..=count {
+            let shares = split(&rng, &SECRET, threshold, count).unwrap();
+            assert_eq!(shares.len(), count as usize);
+            for mask in 1..(1u32 << count) {
+                let chosen = subset(&shares, mask);
+                let combined = combine(&chosen);
+                if chosen.len() >= threshold as usize {
+                    assert_eq!(combined.unwrap().as_bytes(), &SECRET, "{} of {}, shares {:b}", threshold, count, mask);
+                    // The order the shares are given in does not matter
+                    let reversed: Vec<Share> = chosen.iter().rev().map(copy).collect();
+                    assert_eq!(combine(&reversed).unwrap().as_bytes(), &SECRET);
+                } else {
+                    assert!(combined.is_err(), "{} of {}, shares {:b}", threshold, count, mask);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn invalid_splits_are_refused() {
+    let rng = SeededRng::new(b"jpassword shamir");
+    assert!(split(&rng, &SECRET, 0, 3).is_err());
+    assert!(split(&rng, &SECRET, 4, 3).is_err());
+    assert!(combine(&[]).is_err());
+}
+
+#[test]
+fn mismatched_shares_are_refused() {
+    let rng = SeededRng::new(b"jpassword shamir");
+    let shares = split(&rng, &SECRET, 2, 3).unwrap();
+
+    // Too few
+    assert!(combine(&[copy(&shares[0])]).is_err());
+    // The same share twice
+    assert!(combine(&[copy(&shares[0]), copy(&shares[0])]).is_err());
+    let mut same_x = copy(&shares[1]);
+    same_x.x = shares[0].x;
+    assert!(combine(&[copy(&shares[0]), same_x]).is_err());
+    // A share at 0 would hold the secret itself
+    let mut zero_x = copy(&shares[1]);
+    zero_x.x = 0;
+    assert!(combine(&[copy(&shares[0]), zero_x]).is_err());
+
+    // Shares of another split of the same secret
+    let other = split(&rng, &SECRET, 2, 3).unwrap();
+    assert_ne!(other[1].split_id, shares[0].split_id);
+    assert!(combine(&[copy(&shares[0]), copy(&other[1])]).is_err());
+    // Shares claiming different thresholds
+    let mut other_threshold = copy(&shares[1]);
+    other_threshold.threshold = 3;
+    assert!(combine(&[copy(&shares[0]), other_threshold, copy(&shares[2])]).is_err());
+}
+
+#[test]
+fn share_survives_encoding() {
+    let rng = SeededRng::new(b"jpassword shamir");
+    for share in split(&rng, &SECRET, 3, 5).unwrap() {
+        let encoded = encode_share(&share);
+        let decoded = decode_share(std::str::from_utf8(encoded.as_bytes()).unwrap()).unwrap();
+        assert_eq!((decoded.threshold, decoded.x, decoded.split_id), (share.threshold, share.x, share.split_id));
+        assert_eq!(decoded.y.as_bytes(), share.y.as_bytes());
+    }
+    assert!(decode_share("").is_err());
+    assert!(decode_share("not a share").is_err());
+}
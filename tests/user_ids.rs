@@ -0,0 +1,63 @@
+use jpassword::db::create_db_then_pool;
+use rusqlite::{params, Connection};
+use std::{fs, path::PathBuf};
+
+/// Returns an empty directory for a test's database
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("jpassword-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Inserts a User with only a hash and returns its id
+fn insert_user(conn: &Connection, hash: &str) -> i64 {
+    conn.execute("INSERT INTO users (hash) VALUES (?)", params![hash]).unwrap();
+    conn.last_insert_rowid()
+}
+
+#[test]
+fn deleted_user_id_is_not_reused() {
+    let dir = temp_dir("user-ids");
+    let pool = create_db_then_pool(&dir.join("jpassword.db"), 1);
+    let conn = pool.get().unwrap();
+    let deleted = insert_user(&conn, "deleted");
+    conn.execute("DELETE FROM users WHERE id = ?", params![deleted]).unwrap();
+    assert!(insert_user(&conn, "created") > deleted);
+    drop(conn);
+    drop(pool);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn older_database_skips_ids_left_in_audit_trail() {
+    let dir = temp_dir("user-ids-migrate");
+    let file = dir.join("jpassword.db");
+    {
+        let conn = Connection::open(&file).unwrap();
+        conn.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, hash TEXT, password BLOB, salt BLOB, data BLOB)",
+            params![]).unwrap();
+        conn.execute("CREATE UNIQUE INDEX users_hash ON users (hash)", params![]).unwrap();
+        conn.execute("INSERT INTO users (id, hash, data) VALUES (1, 'kept', x'01')", params![]).unwrap();
+        conn.execute("CREATE TABLE audit_log (id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL, \
+            action TEXT NOT NULL, actor TEXT, detail TEXT, created_at INTEGER NOT NULL)", params![]).unwrap();
+        conn.execute("INSERT INTO audit_log (user_id, action, created_at) VALUES (2, 'escrow_wrapped', 0)", params![])
+            .unwrap();
+    }
+
+    let pool = create_db_then_pool(&file, 1);
+    let conn = pool.get().unwrap();
+    let data: Vec<u8> = conn.query_row("SELECT data FROM users WHERE hash = 'kept'", params![], |row| row.get(0))
+        .unwrap();
+    assert_eq!(data, vec![1]);
+    assert_eq!(insert_user(&conn, "created"), 3);
+    assert!(conn.execute("INSERT INTO users (hash) VALUES ('kept')", params![]).is_err());
+    drop(conn);
+    drop(pool);
+
+    // A second startup leaves the rebuilt table alone
+    let pool = create_db_then_pool(&file, 1);
+    assert_eq!(insert_user(&pool.get().unwrap(), "later"), 4);
+    drop(pool);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,200 @@
+mod common;
+
+use jpassword::{crypto::{keypair, secret::SecretString}, db::create_db_then_pool, models::{decoy::DuressAction,
+    device::MAX_PIN_ATTEMPTS, emergency::Access, item::{Item, Note}, user::{User, UserError}}};
+use rusqlite::{params, Connection};
+use std::fs;
+
+/// The blobs of a User's row that are sealed under the User's context
+const SEALED_COLUMNS: &str = "password, vault_key, data, revision";
+
+fn secret(value: &str) -> SecretString {
+    SecretString::from(value.to_string())
+}
+
+/// Returns the number of rows in a table
+fn count(conn: &Connection, table: &str) -> i64 {
+    conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), params![], |row| row.get(0)).unwrap()
+}
+
+#[test]
+fn wrong_pins_destroy_device() {
+    let dir = common::temp_dir("users-device");
+    let settings = common::settings(&dir);
+    let pool = create_db_then_pool(&dir.join("jpassword.db"), 1);
+    let mut conn = pool.get().unwrap();
+    let (user, _) = User::create(&mut conn, &settings, "alice".to_string(), secret("password")).unwrap();
+    let (device, device_secret) = user.enroll_device(&conn, &settings, "phone", &secret("1234")).unwrap();
+    let unlock = |pin: &str| User::unlock_with_device(&conn, &settings, "alice".to_string(), device.id, &device_secret,
+        &secret(pin)).map(|_| ());
+
+    // A good PIN clears the wrong ones tried before it
+    for _ in 1..MAX_PIN_ATTEMPTS {
+        assert_eq!(unlock("0000"), Err(UserError::InvalidCredentials));
+    }
+    assert_eq!(user.devices(&conn).unwrap()[0].attempts, MAX_PIN_ATTEMPTS - 1);
+    assert_eq!(unlock("1234"), Ok(()));
+    assert_eq!(user.devices(&conn).unwrap()[0].attempts, 0);
+
+    for _ in 0..MAX_PIN_ATTEMPTS {
+        assert_eq!(unlock("0000"), Err(UserError::InvalidCredentials));
+    }
+    assert_eq!(count(&conn, "devices"), 0);
+    assert!(user.devices(&conn).unwrap().is_empty());
+    assert_eq!(unlock("1234"), Err(UserError::InvalidCredentials));
+
+    drop(conn);
+    drop(pool);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn blobs_moved_to_another_row_are_refused() {
+    let dir = common::temp_dir("users-moved");
+    let settings = common::settings(&dir);
+    let pool = create_db_then_pool(&dir.join("jpassword.db"), 1);
+    let mut conn = pool.get().unwrap();
+    User::create(&mut conn, &settings, "alice".to_string(), secret("alice password")).unwrap();
+    User::create(&mut conn, &settings, "bob".to_string(), secret("bob password")).unwrap();
+
+    // Alice's password hash is moved along, so the password verifies and only the context tells the rows apart
+    conn.execute(&format!("UPDATE users SET ({}) = (SELECT {} FROM users WHERE id = 1) WHERE id = 2",
+        SEALED_COLUMNS, SEALED_COLUMNS), params![]).unwrap();
+    let login = User::login(&conn, &settings, "bob".to_string(), secret("alice password"));
+    assert_eq!(login.err(), Some(UserError::ContextMismatch));
+
+    drop(conn);
+    drop(pool);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn older_data_is_refused() {
+    let dir = common::temp_dir("users-rollback");
+    let settings = common::settings(&dir);
+    let pool = create_db_then_pool(&dir.join("jpassword.db"), 1);
+    let mut conn = pool.get().unwrap();
+    User::create(&mut conn, &settings, "alice".to_string(), secret("password")).unwrap();
+    let mut user = User::login(&conn, &settings, "alice".to_string(), secret("password")).unwrap();
+    let (old_data, old_revision): (Vec<u8>, i64) = conn.query_row("SELECT data, revision FROM users WHERE id = 1",
+        params![], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+
+    user.credentials.create(settings.rng.as_ref(), "note".to_string(),
+        Item::Note(Note { text: secret("added after the snapshot") })).unwrap();
+    user.save(&conn, &settings).unwrap();
+    let revision: i64 = conn.query_row("SELECT revision FROM users WHERE id = 1", params![], |row| row.get(0)).unwrap();
+    assert!(revision > old_revision);
+
+    // The older data is sealed under its own revision, not the one the row is now at
+    conn.execute("UPDATE users SET data = ? WHERE id = 1", params![old_data]).unwrap();
+    let login = User::login(&conn, &settings, "alice".to_string(), secret("password"));
+    assert_eq!(login.err(), Some(UserError::ContextMismatch));
+
+    drop(conn);
+    drop(pool);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Registers a duress password taking `action` for alice, who has a credential, a device and an escrowed
+/// vault key, logs in with it and checks what still opens the real vault
+///
+/// # Arguments
+///
+/// * `name` - The name of the test
+/// * `action` - The duress action
+fn log_in_under_duress(name: &str, action: DuressAction) {
+    let dir = common::temp_dir(name);
+    let mut settings = common::settings(&dir);
+    settings.duress = true;
+    settings.escrow_public_key = Some(keypair::new_keypair(settings.rng.as_ref()).unwrap().1);
+    let pool = create_db_then_pool(&dir.join("jpassword.db"), 1);
+    let mut conn = pool.get().unwrap();
+    let (mut user, recovery_code) = User::create(&mut conn, &settings, "alice".to_string(), secret("password")).unwrap();
+    user.credentials.create(settings.rng.as_ref(), "note".to_string(), Item::Note(Note { text: secret("real") })).unwrap();
+    user.save(&conn, &settings).unwrap();
+    let (device, device_secret) = user.enroll_device(&conn, &settings, "phone", &secret("1234")).unwrap();
+    User::set_duress_password(&mut conn, &settings, "alice".to_string(), secret("password"), secret("duress"), action)
+        .unwrap();
+    assert_eq!(count(&conn, "escrow"), 1);
+
+    // The duress password opens the empty decoy, never the real credentials
+    let decoy = User::login(&conn, &settings, "alice".to_string(), secret("duress")).unwrap();
+    assert!(decoy.credentials.credentials.is_empty());
+
+    let unlocked = User::unlock_with_device(&conn, &settings, "alice".to_string(), device.id, &device_secret,
+        &secret("1234")).map(|_| ());
+    assert_eq!(unlocked, Err(UserError::InvalidCredentials));
+    assert_eq!(count(&conn, "devices"), 0);
+    let login = User::login(&conn, &settings, "alice".to_string(), secret("password"));
+    assert_eq!(login.err(), Some(UserError::InvalidCredentials));
+
+    let keypair: (Option<Vec<u8>>, Option<Vec<u8>>) = conn.query_row("SELECT public_key, private_key FROM users",
+        params![], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    let recovered = User::recover(&mut conn, &settings, "alice".to_string(), &recovery_code, secret("new password"));
+    match action {
+        DuressAction::Lock => {
+            // The recovery code still opens the real vault and sets a new password
+            assert!(keypair.0.is_some() && keypair.1.is_some());
+            assert_eq!(recovered.unwrap().0.credentials.credentials.len(), 1);
+            let user = User::login(&conn, &settings, "alice".to_string(), secret("new password")).unwrap();
+            assert_eq!(user.credentials.credentials.len(), 1);
+        },
+        DuressAction::Wipe => {
+            assert_eq!(keypair, (None, None));
+            assert_eq!(count(&conn, "key_slots"), 0);
+            assert_eq!(count(&conn, "escrow"), 0);
+            assert_eq!(recovered.err(), Some(UserError::InvalidCredentials));
+        },
+        DuressAction::None => unreachable!(),
+    }
+
+    drop(conn);
+    drop(pool);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn duress_lock_stops_password_and_devices() {
+    log_in_under_duress("users-duress-lock", DuressAction::Lock);
+}
+
+#[test]
+fn duress_wipe_stops_every_way_in() {
+    log_in_under_duress("users-duress-wipe", DuressAction::Wipe);
+}
+
+#[test]
+fn deleted_account_leaves_no_rows() {
+    let dir = common::temp_dir("users-delete");
+    let mut settings = common::settings(&dir);
+    settings.escrow_public_key = Some(keypair::new_keypair(settings.rng.as_ref()).unwrap().1);
+    let pool = create_db_then_pool(&dir.join("jpassword.db"), 1);
+    let mut conn = pool.get().unwrap();
+    let (alice, _) = User::create(&mut conn, &settings, "alice".to_string(), secret("password")).unwrap();
+    let (bob, _) = User::create(&mut conn, &settings, "bob".to_string(), secret("password")).unwrap();
+    alice.enroll_device(&conn, &settings, "phone", &secret("1234")).unwrap();
+    alice.grant_emergency_access(&conn, &settings, "bob", Access::Read, 1).unwrap();
+    bob.grant_emergency_access(&conn, &settings, "alice", Access::Takeover, 1).unwrap();
+    let alice_id: i64 = conn.query_row("SELECT id FROM users ORDER BY id LIMIT 1", params![], |row| row.get(0)).unwrap();
+    let references = |conn: &Connection| -> Vec<i64> {
+        ["users WHERE id", "key_slots WHERE user_id", "devices WHERE user_id", "escrow WHERE user_id",
+            "audit_log WHERE user_id", "emergency_grants WHERE owner_id = ?1 OR grantee_id"].iter()
+            .map(|table| conn.query_row(&format!("SELECT COUNT(*) FROM {} = ?1", table), params![alice_id],
+                |row| row.get(0)).unwrap())
+            .collect()
+    };
+    assert!(references(&conn).iter().all(|count| *count > 0), "{:?}", references(&conn));
+    let bob_slots = bob.slots(&conn).unwrap().len();
+
+    let refused = User::delete_account(&mut conn, &settings, "alice".to_string(), secret("password"), "bob");
+    assert_eq!(refused, Err(UserError::InvalidConfirmation));
+    User::delete_account(&mut conn, &settings, "alice".to_string(), secret("password"), "alice").unwrap();
+    assert_eq!(references(&conn), vec![0; 6]);
+    // Bob's own rows are left alone
+    assert_eq!(count(&conn, "users"), 1);
+    assert_eq!(bob.slots(&conn).unwrap().len(), bob_slots);
+
+    drop(conn);
+    drop(pool);
+    fs::remove_dir_all(&dir).unwrap();
+}
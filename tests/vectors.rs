@@ -0,0 +1,59 @@
+use jpassword::crypto::{aead::{aead_open, aead_open_with_key}, envelope, hash::{Argon2Params, Kdf}};
+use serde_json::Value;
+
+const VECTORS: &str = include_str!("../vectors/envelope.json");
+
+/// Decodes the hex field `name` of a vector, or returns None if it is absent
+fn field(vector: &Value, name: &str) -> Option<Vec<u8>> {
+    vector.get(name).map(|hex_str| hex::decode(hex_str.as_str().unwrap()).unwrap())
+}
+
+/// Reads the `kdf` object of a vector
+fn kdf(vector: &Value) -> Kdf {
+    let kdf = &vector["kdf"];
+    let param = |name: &str| kdf[name].as_u64().unwrap() as u32;
+    match kdf["id"].as_str().unwrap() {
+        "argon2id" => Kdf::Argon2id(Argon2Params {
+            memory_kib: param("memory_kib"),
+            iterations: param("iterations"),
+            parallelism: param("parallelism"),
+        }),
+        "pbkdf2-sha256" => Kdf::Pbkdf2Sha256 { iterations: param("iterations") },
+        id => panic!("unknown kdf {}", id),
+    }
+}
+
+#[test]
+fn every_vector_opens_to_its_plaintext() {
+    let vectors: Vec<Value> = serde_json::from_str(VECTORS).unwrap();
+    assert!(!vectors.is_empty());
+    for vector in &vectors {
+        let description = vector["description"].as_str().unwrap();
+        let sealed = field(vector, "envelope").unwrap();
+        let plaintext = field(vector, "plaintext").unwrap();
+        let context = field(vector, "context").unwrap_or_default();
+
+        let opened = match (field(vector, "key"), field(vector, "password")) {
+            (Some(key), _) => aead_open_with_key(&sealed, &key, &context),
+            (None, Some(password)) => {
+                let kdf = kdf(vector);
+                let salt = envelope::parse(&sealed).unwrap().header.salt;
+                let mut derived = vec![0u8; 32];
+                kdf.derive(&password, &salt, &mut derived).unwrap();
+                assert_eq!(Some(derived), field(vector, "derived_key"), "{}", description);
+                aead_open(&sealed, &password, &kdf, &context)
+            },
+            (None, None) => panic!("{} has neither a key nor a password", description),
+        };
+        assert_eq!(opened.expect(description).as_bytes(), &plaintext[..], "{}", description);
+
+        // Any flipped bit of the envelope must fail to open
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let reopened = match field(vector, "key") {
+            Some(key) => aead_open_with_key(&tampered, &key, &context),
+            None => aead_open(&tampered, &field(vector, "password").unwrap(), &kdf(vector), &context),
+        };
+        assert!(reopened.is_err(), "{}", description);
+    }
+}